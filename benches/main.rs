@@ -5,16 +5,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use std::cell::Cell;
 use std::fs::File;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
 use std::mem::size_of;
 use std::path::Path;
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
-// These are the objects from the vm-memory branch identified as "master" in the experiments,
-// and "vm-memory" in the "dev-dependencies" section of Cargo.toml. Please note that we can also
-// specify a particular commit instead of a brach name in there.
+// These are the objects from the vm-memory release identified as "0.2.2" in the experiments,
+// and "vm-memory" in the "dev-dependencies" section of Cargo.toml. It is pinned to the release
+// whose interfaces `crosvm_mem::vm_memory_adapter` implements.
 use vm_memory::GuestMemoryMmap;
 use vm_memory::{ByteValued, Bytes, GuestAddress};
 
@@ -25,13 +26,11 @@ use vm_memory2::GuestMemoryMmap as GuestMemoryMmap2;
 use vm_memory2::{ByteValued as ByteValued2, Bytes as Bytes2, GuestAddress as GuestAddress2};
 
 // These are the objects from the crosvm guest memory model implementation, that were copy pasted
-// in src/crosvm_mem. Right now we pretty much explicitly invoke measurement code three times
-// for the three guest memory implementations under consideration. When we identify the best
-// long-term benchmarking setup, we can make all of them implement `GuestMemory` and have generic
-// functions/function parameters that remove the need for duplicated code. However, that's not
-// possible right now because, from a Rust type system perspective, a particular version of
-// the vm-memory GuestMemory interface is tied to a specific implementation, because they're
-// both in the same crate. </rant>
+// in src/crosvm_mem. The crosvm `GuestMemory` also implements the vm-memory 0.2.2 interfaces
+// through `crosvm_mem::vm_memory_adapter` when the `vm-memory` feature is enabled, so the
+// `bench_upstream` macro below measures the same code on top of both. The
+// "vm-memory other" interfaces are a different version of the trait definitions from a Rust type
+// system perspective, so they still have to be invoked separately.
 use vm_memory_test::crosvm_mem::{
     DataInit, GuestAddress as CvmGuestAddress, GuestMemory as CvmGuestMemory,
 };
@@ -42,6 +41,10 @@ const REGION_SIZE: u64 = 0x8000_0000;
 const REGIONS_COUNT: u64 = 8;
 const ACCESS_SIZE: usize = 0x200;
 
+// Parameters for the region lookup benchmarks, which use many small regions instead.
+const LOOKUP_REGION_SIZE: u64 = 0x10_0000;
+const LOOKUP_REGIONS_COUNTS: &[u64] = &[1, 8, 128, 1024];
+
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
 struct SmallDummy {
@@ -62,6 +65,25 @@ unsafe impl ByteValued for BigDummy {}
 unsafe impl ByteValued2 for BigDummy {}
 unsafe impl DataInit for BigDummy {}
 
+// Registers a benchmark that runs `$body` with `$mem` bound to each of the implementations of the
+// vm-memory 0.2.2 interfaces in turn. The crosvm one is only available with the `vm-memory`
+// feature.
+macro_rules! bench_upstream {
+    ($g:ident, $memory:expr, $cvmem:expr, |$mem:ident| $body:expr) => {
+        $g.bench_function("vm-memory 0.2.2", |b| {
+            let $mem = &$memory;
+            b.iter(|| black_box($body))
+        });
+        #[cfg(feature = "vm-memory")]
+        {
+            $g.bench_function("crosvm (vm-memory interfaces)", |b| {
+                let $mem = &$cvmem;
+                b.iter(|| black_box($body))
+            });
+        }
+    };
+}
+
 fn make_image(size: usize) -> Vec<u8> {
     let mut image: Vec<u8> = Vec::with_capacity(size as usize);
     for i in 0..size {
@@ -80,15 +102,6 @@ enum AccessKind {
 }
 
 impl AccessKind {
-    // We call this to find out if an access is cross-region, so we skip testing the crosvm
-    // implementation, because it doesn't support cross-region accesses.
-    fn is_cross_region(&self) -> bool {
-        match self {
-            AccessKind::InRegion(_) => false,
-            AccessKind::CrossRegion(_) => true,
-        }
-    }
-
     fn make_offset(&self, access_size: usize) -> u64 {
         match *self {
             AccessKind::InRegion(idx) => REGION_SIZE * idx,
@@ -145,6 +158,11 @@ fn cbenchmark(c: &mut Criterion) {
     let mut file = File::open(Path::new("/dev/zero")).unwrap();
     let temp = TempFile::new().unwrap();
     let mut file_to_write = temp.as_file();
+    // The copy benchmarks use a file with `ACCESS_SIZE` bytes in it, at a fixed offset, so both
+    // the source and destination stay the same size.
+    let copy_temp = TempFile::new().unwrap();
+    let mut file_to_copy = copy_temp.as_file();
+    file_to_copy.write_all(&image).unwrap();
 
     let accesses = &[
         AccessKind::InRegion(0),
@@ -160,14 +178,9 @@ fn cbenchmark(c: &mut Criterion) {
         {
             let mut g = c.benchmark_group(format!("read_from_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| {
-                    black_box(
-                        memory
-                            .read_from(GuestAddress(off), &mut Cursor::new(&image), ACCESS_SIZE)
-                            .unwrap(),
-                    )
-                })
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_from(GuestAddress(off), &mut Cursor::new(&image), ACCESS_SIZE)
+                    .unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
@@ -180,61 +193,55 @@ fn cbenchmark(c: &mut Criterion) {
                 })
             });
 
-            // There doesn't seem to be an equivalent method in crosvm anymore.
+            // There doesn't seem to be an equivalent native method in crosvm anymore.
         }
 
         {
             let mut g = c.benchmark_group(format!("read_from_file_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_from(GuestAddress(off), &mut file, ACCESS_SIZE)
+                    .unwrap()
+            });
+
+            g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .read_from(GuestAddress(off), &mut file, ACCESS_SIZE)
+                        memory2
+                            .read_from(GuestAddress2(off), &mut file, ACCESS_SIZE)
                             .unwrap(),
                     )
                 })
             });
 
-            g.bench_function("vm-memory other", |b| {
+            g.bench_function("crosvm", |b| {
                 b.iter(|| {
                     black_box(
-                        memory2
-                            .read_from(GuestAddress2(off), &mut file, ACCESS_SIZE)
+                        cvmem
+                            .read_to_memory(CvmGuestAddress(off), &file, ACCESS_SIZE)
                             .unwrap(),
                     )
                 })
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .read_to_memory(CvmGuestAddress(off), &file, ACCESS_SIZE)
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
-        }
-
-        {
-            let mut g = c.benchmark_group(format!("read_exact_from_{:#0x}", off).as_str());
-
-            g.bench_function("vm-memory master", |b| {
+            g.bench_function("crosvm (positioned)", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .read_exact_from(
-                                GuestAddress(off),
-                                &mut Cursor::new(&mut image),
-                                ACCESS_SIZE,
-                            )
+                        cvmem
+                            .read_to_memory_at(CvmGuestAddress(off), &file, 0, ACCESS_SIZE)
                             .unwrap(),
                     )
                 })
             });
+        }
+
+        {
+            let mut g = c.benchmark_group(format!("read_exact_from_{:#0x}", off).as_str());
+
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_exact_from(GuestAddress(off), &mut Cursor::new(&mut image), ACCESS_SIZE)
+                    .unwrap()
+            });
 
             g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
@@ -250,14 +257,14 @@ fn cbenchmark(c: &mut Criterion) {
                 })
             });
 
-            // There doesn't seem to be an equivalent method in crosvm anymore.
+            // There doesn't seem to be an equivalent native method in crosvm anymore.
         }
 
         {
             let mut g = c.benchmark_group(format!("read_entire_slice_from_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| black_box(memory.read_slice(&mut buf[..], GuestAddress(off)).unwrap()))
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_slice(&mut buf[..], GuestAddress(off)).unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
@@ -270,86 +277,74 @@ fn cbenchmark(c: &mut Criterion) {
                 })
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .read_exact_at_addr(&mut buf[..], CvmGuestAddress(off))
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
+            g.bench_function("crosvm", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .read_exact_at_addr(&mut buf[..], CvmGuestAddress(off))
+                            .unwrap(),
+                    )
+                })
+            });
         }
 
         {
             let mut g = c.benchmark_group(format!("read_slice_from_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| black_box(memory.read(&mut buf[..], GuestAddress(off)).unwrap()))
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read(&mut buf[..], GuestAddress(off)).unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
                 b.iter(|| black_box(memory2.read(&mut buf[..], GuestAddress2(off)).unwrap()))
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .read_at_addr(&mut buf[..], CvmGuestAddress(off))
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
+            g.bench_function("crosvm", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .read_at_addr(&mut buf[..], CvmGuestAddress(off))
+                            .unwrap(),
+                    )
+                })
+            });
         }
 
         {
             let obj_off = access.make_offset(size_of::<SmallDummy>());
             let mut g = c.benchmark_group(format!("read_small_obj_from_{:#0x}", obj_off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_obj::<SmallDummy>(GuestAddress(obj_off)).unwrap()
+            });
+
+            g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .read_obj::<SmallDummy>(GuestAddress(obj_off))
+                        memory2
+                            .read_obj::<SmallDummy>(GuestAddress2(obj_off))
                             .unwrap(),
                     )
                 })
             });
 
-            g.bench_function("vm-memory other", |b| {
+            g.bench_function("crosvm", |b| {
                 b.iter(|| {
                     black_box(
-                        memory2
-                            .read_obj::<SmallDummy>(GuestAddress2(obj_off))
+                        cvmem
+                            .read_obj_from_addr::<SmallDummy>(CvmGuestAddress(obj_off))
                             .unwrap(),
                     )
                 })
             });
-
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .read_obj_from_addr::<SmallDummy>(CvmGuestAddress(obj_off))
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
         }
 
         {
             let obj_off = access.make_offset(size_of::<BigDummy>());
             let mut g = c.benchmark_group(format!("read_big_obj_from_{:#0x}", obj_off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| black_box(memory.read_obj::<BigDummy>(GuestAddress(obj_off)).unwrap()))
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_obj::<BigDummy>(GuestAddress(obj_off)).unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
@@ -362,17 +357,15 @@ fn cbenchmark(c: &mut Criterion) {
                 })
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .read_obj_from_addr::<BigDummy>(CvmGuestAddress(obj_off))
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
+            g.bench_function("crosvm", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .read_obj_from_addr::<BigDummy>(CvmGuestAddress(obj_off))
+                            .unwrap(),
+                    )
+                })
+            });
         }
 
         // Write stuff.
@@ -380,14 +373,9 @@ fn cbenchmark(c: &mut Criterion) {
         {
             let mut g = c.benchmark_group(format!("write_to_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| {
-                    black_box(
-                        memory
-                            .write_to(GuestAddress(off), &mut Cursor::new(&mut image), ACCESS_SIZE)
-                            .unwrap(),
-                    )
-                })
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.write_to(GuestAddress(off), &mut Cursor::new(&mut image), ACCESS_SIZE)
+                    .unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
@@ -404,61 +392,116 @@ fn cbenchmark(c: &mut Criterion) {
                 })
             });
 
-            // There doesn't seem to be an equivalent method in crosvm anymore.
+            // There doesn't seem to be an equivalent native method in crosvm anymore.
         }
 
         {
             let mut g = c.benchmark_group(format!("write_to_file_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.write_to(GuestAddress(off), &mut file_to_write, ACCESS_SIZE)
+                    .unwrap()
+            });
+
+            g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .write_to(GuestAddress(off), &mut file_to_write, ACCESS_SIZE)
+                        memory2
+                            .write_to(GuestAddress2(off), &mut file_to_write, ACCESS_SIZE)
                             .unwrap(),
                     )
                 })
             });
 
-            g.bench_function("vm-memory other", |b| {
+            g.bench_function("crosvm", |b| {
                 b.iter(|| {
                     black_box(
-                        memory2
-                            .write_to(GuestAddress2(off), &mut file_to_write, ACCESS_SIZE)
+                        cvmem
+                            .write_from_memory(CvmGuestAddress(off), file_to_write, ACCESS_SIZE)
                             .unwrap(),
                     )
                 })
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .write_from_memory(CvmGuestAddress(off), file_to_write, ACCESS_SIZE)
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
+            // Unlike the other variants, this keeps rewriting the start of the file instead of
+            // growing it.
+            g.bench_function("crosvm (positioned)", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .write_from_memory_at(
+                                CvmGuestAddress(off),
+                                file_to_write,
+                                0,
+                                ACCESS_SIZE,
+                            )
+                            .unwrap(),
+                    )
+                })
+            });
         }
 
+        // Compare copying within the kernel against copying through the guest memory mapping.
         {
-            let mut g = c.benchmark_group(format!("write_exact_to_{:#0x}", off).as_str());
+            let mut g = c.benchmark_group(format!("copy_from_file_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
+            g.bench_function("crosvm (copy_file_range)", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .write_all_to(
-                                GuestAddress(off),
-                                &mut Cursor::new(&mut image),
+                        cvmem
+                            .copy_from_file(CvmGuestAddress(off), file_to_copy, 0, ACCESS_SIZE)
+                            .unwrap(),
+                    )
+                })
+            });
+
+            g.bench_function("crosvm (read_to_memory_at)", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .read_to_memory_at(CvmGuestAddress(off), file_to_copy, 0, ACCESS_SIZE)
+                            .unwrap(),
+                    )
+                })
+            });
+        }
+
+        {
+            let mut g = c.benchmark_group(format!("copy_to_file_{:#0x}", off).as_str());
+
+            g.bench_function("crosvm (copy_file_range)", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .copy_to_file(CvmGuestAddress(off), file_to_copy, 0, ACCESS_SIZE)
+                            .unwrap(),
+                    )
+                })
+            });
+
+            g.bench_function("crosvm (write_from_memory_at)", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .write_from_memory_at(
+                                CvmGuestAddress(off),
+                                file_to_copy,
+                                0,
                                 ACCESS_SIZE,
                             )
                             .unwrap(),
                     )
                 })
             });
+        }
+
+        {
+            let mut g = c.benchmark_group(format!("write_exact_to_{:#0x}", off).as_str());
+
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.write_all_to(GuestAddress(off), &mut Cursor::new(&mut image), ACCESS_SIZE)
+                    .unwrap()
+            });
 
             g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
@@ -474,131 +517,178 @@ fn cbenchmark(c: &mut Criterion) {
                 })
             });
 
-            // There doesn't seem to be an equivalent method in crosvm anymore.
+            // There doesn't seem to be an equivalent native method in crosvm anymore.
         }
 
         {
             let mut g = c.benchmark_group(format!("write_entire_slice_to_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| black_box(memory.write_slice(buf, GuestAddress(off)).unwrap()))
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.write_slice(buf, GuestAddress(off)).unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
                 b.iter(|| black_box(memory2.write_slice(buf, GuestAddress2(off)).unwrap()))
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .write_all_at_addr(&buf[..], CvmGuestAddress(off))
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
+            g.bench_function("crosvm", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .write_all_at_addr(&buf[..], CvmGuestAddress(off))
+                            .unwrap(),
+                    )
+                })
+            });
         }
 
         {
             let mut g = c.benchmark_group(format!("read_slice_from_{:#0x}", off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
-                b.iter(|| black_box(memory.read(buf, GuestAddress(off)).unwrap()))
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read(buf, GuestAddress(off)).unwrap()
             });
 
             g.bench_function("vm-memory other", |b| {
                 b.iter(|| black_box(memory2.read(buf, GuestAddress2(off)).unwrap()))
             });
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(cvmem.write_at_addr(&buf[..], CvmGuestAddress(off)).unwrap())
-                    })
-                });
-            }
+            g.bench_function("crosvm", |b| {
+                b.iter(|| black_box(cvmem.write_at_addr(&buf[..], CvmGuestAddress(off)).unwrap()))
+            });
         }
 
         {
             let obj_off = access.make_offset(size_of::<SmallDummy>());
             let mut g = c.benchmark_group(format!("write_small_obj_to_{:#0x}", obj_off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.write_obj::<SmallDummy>(some_small_dummy, GuestAddress(obj_off))
+                    .unwrap()
+            });
+
+            g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .write_obj::<SmallDummy>(some_small_dummy, GuestAddress(obj_off))
+                        memory2
+                            .write_obj::<SmallDummy>(some_small_dummy, GuestAddress2(obj_off))
                             .unwrap(),
                     )
                 })
             });
 
-            g.bench_function("vm-memory other", |b| {
+            g.bench_function("crosvm", |b| {
                 b.iter(|| {
                     black_box(
-                        memory2
-                            .write_obj::<SmallDummy>(some_small_dummy, GuestAddress2(obj_off))
+                        cvmem
+                            .write_obj_at_addr::<SmallDummy>(
+                                some_small_dummy,
+                                CvmGuestAddress(obj_off),
+                            )
                             .unwrap(),
                     )
                 })
             });
-
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .write_obj_at_addr::<SmallDummy>(
-                                    some_small_dummy,
-                                    CvmGuestAddress(obj_off),
-                                )
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
         }
 
         {
             let obj_off = access.make_offset(size_of::<BigDummy>());
             let mut g = c.benchmark_group(format!("write_big_obj_to_{:#0x}", obj_off).as_str());
 
-            g.bench_function("vm-memory master", |b| {
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.write_obj::<BigDummy>(some_big_dummy, GuestAddress(obj_off))
+                    .unwrap()
+            });
+
+            g.bench_function("vm-memory other", |b| {
                 b.iter(|| {
                     black_box(
-                        memory
-                            .write_obj::<BigDummy>(some_big_dummy, GuestAddress(obj_off))
+                        memory2
+                            .write_obj::<BigDummy>(some_big_dummy, GuestAddress2(obj_off))
                             .unwrap(),
                     )
                 })
             });
 
-            g.bench_function("vm-memory other", |b| {
+            g.bench_function("crosvm", |b| {
                 b.iter(|| {
                     black_box(
-                        memory2
-                            .write_obj::<BigDummy>(some_big_dummy, GuestAddress2(obj_off))
+                        cvmem
+                            .write_obj_at_addr::<BigDummy>(some_big_dummy, CvmGuestAddress(obj_off))
+                            .unwrap(),
+                    )
+                })
+            });
+        }
+    }
+}
+
+fn lookup_benchmark(c: &mut Criterion) {
+    for &count in LOOKUP_REGIONS_COUNTS {
+        let regions = (0..count)
+            .map(|i| (i * LOOKUP_REGION_SIZE, LOOKUP_REGION_SIZE as usize))
+            .collect::<Vec<_>>();
+
+        let memory = GuestMemoryMmap::from_ranges(
+            regions
+                .iter()
+                .map(|pair| (GuestAddress(pair.0), pair.1))
+                .collect::<Vec<_>>()
+                .as_slice(),
+        )
+        .unwrap();
+
+        let cvmem = CvmGuestMemory::new(
+            regions
+                .iter()
+                .map(|pair| (CvmGuestAddress(pair.0), pair.1 as u64))
+                .collect::<Vec<_>>()
+                .as_slice(),
+        )
+        .unwrap();
+
+        // Accesses that keep hitting the last region.
+        {
+            let off = (count - 1) * LOOKUP_REGION_SIZE;
+            let mut g = c.benchmark_group(format!("lookup_same_region_{}", count).as_str());
+
+            bench_upstream!(g, memory, cvmem, |mem| {
+                mem.read_obj::<u64>(GuestAddress(off)).unwrap()
+            });
+
+            g.bench_function("crosvm", |b| {
+                b.iter(|| {
+                    black_box(
+                        cvmem
+                            .read_obj_from_addr::<u64>(CvmGuestAddress(off))
                             .unwrap(),
                     )
                 })
             });
+        }
 
-            if !access.is_cross_region() {
-                g.bench_function("crosvm", |b| {
-                    b.iter(|| {
-                        black_box(
-                            cvmem
-                                .write_obj_at_addr::<BigDummy>(
-                                    some_big_dummy,
-                                    CvmGuestAddress(obj_off),
-                                )
-                                .unwrap(),
-                        )
-                    })
-                });
-            }
+        // Accesses that go to a different region every time.
+        {
+            let mut g = c.benchmark_group(format!("lookup_spread_{}", count).as_str());
+
+            let idx = Cell::new(0);
+            bench_upstream!(g, memory, cvmem, |mem| {
+                idx.set((idx.get() + 1) % count);
+                mem.read_obj::<u64>(GuestAddress(idx.get() * LOOKUP_REGION_SIZE))
+                    .unwrap()
+            });
+
+            g.bench_function("crosvm", |b| {
+                let mut idx = 0;
+                b.iter(|| {
+                    idx = (idx + 1) % count;
+                    black_box(
+                        cvmem
+                            .read_obj_from_addr::<u64>(CvmGuestAddress(idx * LOOKUP_REGION_SIZE))
+                            .unwrap(),
+                    )
+                })
+            });
         }
     }
 }
@@ -609,7 +699,7 @@ criterion_group! {
     // measurement time should smooth out the outliers, but also makes the process run
     // a lot longer.
     config = Criterion::default().sample_size(200).measurement_time(std::time::Duration::from_secs(30));
-    targets = cbenchmark
+    targets = cbenchmark, lookup_benchmark
 }
 
 criterion_main! {
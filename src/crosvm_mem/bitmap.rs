@@ -0,0 +1,118 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Bitmaps that track which pages of a memory region have been written to.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A bitmap with one bit for each page of a memory region, which can be set and harvested
+/// concurrently from any number of threads.
+pub struct AtomicBitmap {
+    map: Vec<AtomicU64>,
+    page_count: usize,
+    page_size: usize,
+}
+
+impl AtomicBitmap {
+    /// Creates a bitmap with all bits cleared, tracking `byte_size` bytes in pages of `page_size`
+    /// bytes.
+    pub fn new(byte_size: usize, page_size: usize) -> AtomicBitmap {
+        // Round up, so that a trailing partial page gets its own bit too.
+        let page_count = (byte_size + page_size - 1) / page_size;
+        let word_count = (page_count + 63) / 64;
+
+        AtomicBitmap {
+            map: (0..word_count).map(|_| AtomicU64::new(0)).collect(),
+            page_count,
+            page_size,
+        }
+    }
+
+    /// Returns the number of pages tracked by this bitmap.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    /// Returns the size in bytes of the pages tracked by this bitmap.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Sets the bits of all the pages that intersect the `len` bytes starting at `offset`.
+    ///
+    /// The part of the range that is past the end of the bitmap is ignored.
+    pub fn set_range(&self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = offset / self.page_size;
+        let last = offset.saturating_add(len - 1) / self.page_size;
+        for page in first..=last {
+            if page >= self.page_count {
+                break;
+            }
+            self.map[page / 64].fetch_or(1 << (page % 64), Ordering::SeqCst);
+        }
+    }
+
+    /// Returns true if the bit of the page that contains `offset` is set.
+    pub fn is_set(&self, offset: usize) -> bool {
+        let page = offset / self.page_size;
+        page < self.page_count
+            && self.map[page / 64].load(Ordering::SeqCst) & (1 << (page % 64)) != 0
+    }
+
+    /// Returns the contents of the bitmap and clears it in a single step for each word, so no
+    /// concurrently set bit is lost.
+    ///
+    /// Bit `i % 64` of the word at index `i / 64` corresponds to the `i`th page.
+    pub fn get_and_reset(&self) -> Vec<u64> {
+        self.map
+            .iter()
+            .map(|word| word.swap(0, Ordering::SeqCst))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmap_size() {
+        let b = AtomicBitmap::new(0x1000 * 64, 0x1000);
+        assert_eq!(b.page_count(), 64);
+        assert_eq!(b.get_and_reset().len(), 1);
+
+        let b = AtomicBitmap::new(0x1000 * 64 + 1, 0x1000);
+        assert_eq!(b.page_count(), 65);
+        assert_eq!(b.get_and_reset().len(), 2);
+    }
+
+    #[test]
+    fn bitmap_set_range() {
+        let b = AtomicBitmap::new(0x1000 * 128, 0x1000);
+        b.set_range(0xfff, 2);
+        b.set_range(0x1000 * 63, 0x1001);
+        b.set_range(0x1000 * 100, 0);
+        // Only the part inside the bitmap is set.
+        b.set_range(0x1000 * 127, 0x3000);
+
+        assert!(b.is_set(0));
+        assert!(b.is_set(0x1000));
+        assert!(!b.is_set(0x2000));
+        assert!(b.is_set(0x1000 * 64));
+        assert!(!b.is_set(0x1000 * 65));
+        assert!(!b.is_set(0x1000 * 100));
+        assert!(!b.is_set(0x1000 * 128));
+
+        let map = b.get_and_reset();
+        assert_eq!(map, vec![0x8000_0000_0000_0003, 0x8000_0000_0000_0001]);
+        assert_eq!(b.get_and_reset(), vec![0, 0]);
+        assert!(!b.is_set(0));
+    }
+}
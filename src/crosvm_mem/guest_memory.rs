@@ -4,18 +4,26 @@
 
 //! Track memory regions that are mapped to the guest VM.
 
+use std::cmp::min;
 use std::convert::AsRef;
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
-use std::mem::size_of;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::fs::File;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
 use std::result;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use libc::{c_int, iovec, off_t};
+
+use super::bitmap::AtomicBitmap;
 use super::data_init::DataInit;
 use super::guest_address::GuestAddress;
-use super::mmap::{self, MappedRegion, MemoryMapping};
-use super::shm::{MemfdSeals, SharedMemory};
+use super::mmap::{self, Advice, MappedRegion, MemoryMapping, Protection};
+use super::shm::{HugePageSize, MemfdSeals, SharedMemory, SharedMemoryBacking};
 use super::volatile_memory::*;
 use super::{errno, pagesize};
 
@@ -28,9 +36,19 @@ pub enum Error {
     MemoryRegionOverlap,
     MemoryRegionTooLarge(u64),
     MemoryNotAligned,
+    HotplugNotEnabled,
+    HotplugLockPoisoned,
+    MmioRegion(GuestAddress),
+    NotMemfdBacked(GuestAddress),
+    AnonymousRegion(GuestAddress),
+    ReadOnlyRegion(GuestAddress),
+    RegionContentsTooLarge(usize),
     MemoryCreationFailed(errno::Error),
     MemorySetSizeFailed(errno::Error),
     MemoryAddSealsFailed(errno::Error),
+    RegionOutsideMemfd { offset: u64, size: u64 },
+    RegionOutsideFile { offset: u64, size: u64 },
+    FileMetadata(io::Error),
     ShortWrite { expected: usize, completed: usize },
     ShortRead { expected: usize, completed: usize },
     SplitOutOfBounds(usize),
@@ -56,10 +74,44 @@ impl Display for Error {
             MemoryMappingFailed(e) => write!(f, "failed to map guest memory: {}", e),
             MemoryRegionOverlap => write!(f, "memory regions overlap"),
             MemoryRegionTooLarge(size) => write!(f, "memory region size {} is too large", size),
-            MemoryNotAligned => write!(f, "memfd regions must be page aligned"),
+            MemoryNotAligned => write!(f, "memory regions must be page aligned"),
+            HotplugNotEnabled => write!(f, "the guest memory memfd can't grow to hotplug regions"),
+            HotplugLockPoisoned => write!(f, "a previous hotplug operation panicked"),
+            MmioRegion(addr) => write!(
+                f,
+                "guest address {} is in an MMIO region, which has no host memory",
+                addr
+            ),
+            NotMemfdBacked(addr) => write!(
+                f,
+                "guest address {} is not backed by the guest memory memfd",
+                addr
+            ),
+            AnonymousRegion(addr) => write!(
+                f,
+                "guest address {} is in an anonymous region, which has no fd",
+                addr
+            ),
             MemoryCreationFailed(_) => write!(f, "failed to create memfd region"),
             MemorySetSizeFailed(e) => write!(f, "failed to set memfd region size: {}", e),
             MemoryAddSealsFailed(e) => write!(f, "failed to set seals on memfd region: {}", e),
+            RegionOutsideMemfd { offset, size } => write!(
+                f,
+                "region of {} bytes at memfd offset {:#x} doesn't fit in the memfd",
+                size, offset
+            ),
+            RegionOutsideFile { offset, size } => write!(
+                f,
+                "region of {} bytes at file offset {:#x} doesn't fit in the file",
+                size, offset
+            ),
+            FileMetadata(e) => write!(f, "failed to get the size of a region file: {}", e),
+            ReadOnlyRegion(addr) => write!(f, "guest address {} is in a read-only region", addr),
+            RegionContentsTooLarge(size) => write!(
+                f,
+                "initial contents of {} bytes don't fit in the memory region",
+                size
+            ),
             ShortWrite {
                 expected,
                 completed,
@@ -82,14 +134,38 @@ impl Display for Error {
     }
 }
 
-struct MemoryRegion {
-    mapping: MemoryMapping,
+/// A region of guest memory that is mapped in the host.
+///
+/// This is part of the public API because it is the region type of the upstream vm-memory
+/// interfaces, which is how regions are handed out when the `vm-memory` feature is enabled. It
+/// has no public constructor, and without the feature no public method returns or inspects one.
+#[derive(Clone)]
+pub struct MemoryRegion {
+    // Shared between the `GuestMemory` instances that contain this region, so its host address
+    // stays the same across hotplug operations.
+    mapping: Arc<MemoryMapping>,
     guest_base: GuestAddress,
+    // Offset of the region in the shared memfd. Always 0 for other backings; file-backed regions
+    // carry their offset in `backing`.
     memfd_offset: u64,
+    // Releases the range of the shared memfd once the last `GuestMemory` that contains the region
+    // is dropped. `None` for other backings and for memfd ranges that weren't allocated here.
+    _memfd_range: Option<Arc<MemfdRange>>,
+    // Keeps the file that backs the region open as long as it is mapped, so it can be passed to
+    // other processes at any time.
+    backing: RegionBacking,
+    prot: Protection,
+    name: Option<String>,
+    // Pages written since the bitmap was last harvested, if dirty tracking is enabled.
+    dirty: Option<Arc<AtomicBitmap>>,
 }
 
 impl MemoryRegion {
-    fn start(&self) -> GuestAddress {
+    pub(crate) fn mapping(&self) -> &MemoryMapping {
+        &self.mapping
+    }
+
+    pub(crate) fn start(&self) -> GuestAddress {
         self.guest_base
     }
 
@@ -101,14 +177,202 @@ impl MemoryRegion {
     fn contains(&self, addr: GuestAddress) -> bool {
         addr >= self.guest_base && addr < self.end()
     }
+
+    pub(crate) fn writable(&self) -> bool {
+        self.prot.is_writable()
+    }
+
+    pub(crate) fn protection(&self) -> Protection {
+        self.prot
+    }
+
+    pub(crate) fn backing(&self) -> &RegionBacking {
+        &self.backing
+    }
+
+    pub(crate) fn memfd_offset(&self) -> u64 {
+        self.memfd_offset
+    }
+
+    // Returns the fd that backs the region and the offset of the region in it, or `None` for
+    // anonymous regions. `memfd` must be the shared memfd of the `GuestMemory` the region is in.
+    pub(crate) fn fd_offset<'a>(
+        &'a self,
+        memfd: &'a SharedMemory,
+    ) -> Option<(&'a dyn AsRawFd, u64)> {
+        match &self.backing {
+            RegionBacking::SharedMemfd => Some((memfd, self.memfd_offset)),
+            RegionBacking::Anonymous => None,
+            RegionBacking::File(file, file_offset) => Some((file.as_ref(), *file_offset)),
+        }
+    }
+
+    pub(crate) fn mark_dirty(&self, offset: usize, len: usize) {
+        if let Some(dirty) = &self.dirty {
+            dirty.set_range(offset, len);
+        }
+    }
+}
+
+// Keeps track of the ranges of the shared memfd that are used by regions.
+struct MemfdSpace {
+    // Size of the memfd. Regions that don't fit in a free range are allocated at the end.
+    size: u64,
+    // Ranges that were released by removed regions, as (offset, size) pairs sorted by offset.
+    // Adjacent ranges are merged, and their pages have been released, so they read as zeroes.
+    free: Vec<(u64, u64)>,
+}
+
+impl MemfdSpace {
+    fn new(size: u64) -> MemfdSpace {
+        MemfdSpace {
+            size,
+            free: Vec::new(),
+        }
+    }
+
+    // Returns the index of the first free range that can hold `size` bytes.
+    fn find_free(&self, size: u64) -> Option<usize> {
+        self.free.iter().position(|&(_, len)| len >= size)
+    }
+
+    // Takes `size` bytes from the start of the free range at `index`.
+    fn take_free(&mut self, index: usize, size: u64) {
+        let (offset, len) = self.free[index];
+        if len == size {
+            self.free.remove(index);
+        } else {
+            self.free[index] = (offset + size, len - size);
+        }
+    }
+
+    fn release(&mut self, offset: u64, size: u64) {
+        let index = self
+            .free
+            .iter()
+            .position(|&(start, _)| start > offset)
+            .unwrap_or(self.free.len());
+        self.free.insert(index, (offset, size));
+        if index + 1 < self.free.len() && offset + size == self.free[index + 1].0 {
+            self.free[index].1 += self.free[index + 1].1;
+            self.free.remove(index + 1);
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == offset {
+            self.free[index - 1].1 += self.free[index].1;
+            self.free.remove(index);
+        }
+    }
+}
+
+// A range of the shared memfd that is used by a region, and is returned to `MemfdSpace` when
+// dropped.
+struct MemfdRange {
+    memfd: Arc<SharedMemory>,
+    space: Arc<Mutex<MemfdSpace>>,
+    offset: u64,
+    size: u64,
+}
+
+impl MemfdRange {
+    fn new(
+        memfd: &Arc<SharedMemory>,
+        space: &Arc<Mutex<MemfdSpace>>,
+        offset: u64,
+        size: u64,
+    ) -> Option<Arc<MemfdRange>> {
+        Some(Arc::new(MemfdRange {
+            memfd: memfd.clone(),
+            space: space.clone(),
+            offset,
+            size,
+        }))
+    }
+}
+
+impl Drop for MemfdRange {
+    fn drop(&mut self) {
+        // The range is only reused if its old contents are gone, so a hotplugged region always
+        // starts out zeroed. Otherwise it's leaked, like before it was dropped.
+        if self.memfd.punch_hole(self.offset, self.size).is_ok() {
+            if let Ok(mut space) = self.space.lock() {
+                space.release(self.offset, self.size);
+            }
+        }
+    }
+}
+
+/// Handles the guest accesses to an MMIO region of a `GuestMemory`.
+///
+/// `offset` is relative to the start of the region, and the accesses never extend past its end.
+pub trait MmioHandler: Send + Sync {
+    /// Fills `data` with the contents of the region at `offset`.
+    fn read(&self, offset: u64, data: &mut [u8]);
+    /// Writes `data` to the region at `offset`.
+    fn write(&self, offset: u64, data: &[u8]);
+}
+
+#[derive(Clone)]
+struct MmioRegion {
+    guest_base: GuestAddress,
+    size: u64,
+    handler: Arc<dyn MmioHandler>,
+}
+
+impl MmioRegion {
+    fn end(&self) -> GuestAddress {
+        // unchecked_add is safe as the region bounds were checked when it was created.
+        self.guest_base.unchecked_add(self.size)
+    }
+
+    fn contains(&self, addr: GuestAddress) -> bool {
+        addr >= self.guest_base && addr < self.end()
+    }
+
+    // Returns the offset of `addr` in the region, and how many of `len` bytes from there fit in
+    // it. `addr` must be in the region.
+    fn access_range(&self, addr: GuestAddress, len: usize) -> (u64, usize) {
+        let offset = addr.offset_from(self.guest_base);
+        (offset, min(len as u64, self.size - offset) as usize)
+    }
 }
 
 /// Tracks a memory region and where it is mapped in the guest, along with a shm
 /// fd of the underlying memory regions.
-#[derive(Clone)]
+///
+/// Regions can also be backed by other files, such as disk images or memfds created by another
+/// process. `region_fd` and `offset_from_region_fd` locate any address in the fd of its own region.
 pub struct GuestMemory {
+    // Sorted by guest address, and guaranteed not to overlap.
     regions: Arc<Vec<MemoryRegion>>,
+    // Sorted by guest address, and guaranteed not to overlap each other or `regions`.
+    mmio: Arc<Vec<MmioRegion>>,
     memfd: Arc<SharedMemory>,
+    // Used and free ranges of the memfd, shared by every `GuestMemory` derived from the same
+    // `GuestMemory::new` call so hotplugged regions never get overlapping ranges of the memfd.
+    memfd_space: Arc<Mutex<MemfdSpace>>,
+    track_dirty: bool,
+    // Page size of the memfd, if it's backed by huge pages.
+    huge_page_size: Option<HugePageSize>,
+    // Size of the inaccessible guard areas around each region's mapping, or 0 if there are none.
+    guard_size: usize,
+    // Index of the region that satisfied the last lookup. This is only a hint, so it's kept per
+    // instance rather than shared between clones to avoid bouncing it between threads.
+    last_region: AtomicUsize,
+}
+
+impl Clone for GuestMemory {
+    fn clone(&self) -> GuestMemory {
+        GuestMemory {
+            regions: self.regions.clone(),
+            mmio: self.mmio.clone(),
+            memfd: self.memfd.clone(),
+            memfd_space: self.memfd_space.clone(),
+            track_dirty: self.track_dirty,
+            huge_page_size: self.huge_page_size,
+            guard_size: self.guard_size,
+            last_region: AtomicUsize::new(self.last_region.load(Ordering::Relaxed)),
+        }
+    }
 }
 
 impl AsRawFd for GuestMemory {
@@ -125,30 +389,36 @@ impl AsRef<SharedMemory> for GuestMemory {
 
 impl GuestMemory {
     /// Creates backing memfd for GuestMemory regions
-    fn create_memfd(ranges: &[(GuestAddress, u64)]) -> Result<SharedMemory> {
-        let mut aligned_size = 0;
-        let pg_size = pagesize();
-        for range in ranges {
-            if range.1 % pg_size as u64 != 0 {
-                return Err(Error::MemoryNotAligned);
-            }
-
-            aligned_size += range.1;
-        }
-
+    fn create_memfd(
+        aligned_size: u64,
+        huge_page_size: Option<HugePageSize>,
+        hotplug: bool,
+    ) -> Result<SharedMemory> {
         let mut seals = MemfdSeals::new();
 
+        // Growing is only allowed if regions will be hotplugged later. It can't invalidate any of
+        // the existing mappings, unlike shrinking.
+        if !hotplug {
+            seals.set_grow_seal();
+        }
         seals.set_shrink_seal();
-        seals.set_grow_seal();
         seals.set_seal_seal();
 
-        let mut memfd = SharedMemory::named("crosvm_guest").map_err(Error::MemoryCreationFailed)?;
+        let mut memfd = match huge_page_size {
+            Some(page_size) => SharedMemory::named_hugetlb("crosvm_guest", page_size),
+            None => SharedMemory::named("crosvm_guest"),
+        }
+        .map_err(Error::MemoryCreationFailed)?;
         memfd
             .set_size(aligned_size)
             .map_err(Error::MemorySetSizeFailed)?;
-        memfd
-            .add_seals(seals)
-            .map_err(Error::MemoryAddSealsFailed)?;
+        // The fallbacks for when memfds aren't available can't be sealed, but a real memfd has to
+        // be.
+        if memfd.backing() == SharedMemoryBacking::Memfd {
+            memfd
+                .add_seals(seals)
+                .map_err(Error::MemoryAddSealsFailed)?;
+        }
 
         Ok(memfd)
     }
@@ -156,43 +426,434 @@ impl GuestMemory {
     /// Creates a container for guest memory regions.
     /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
     pub fn new(ranges: &[(GuestAddress, u64)]) -> Result<GuestMemory> {
-        // Create memfd
+        GuestMemory::create(ranges, false, None)
+    }
 
-        let memfd = GuestMemory::create_memfd(ranges)?;
-        // Create memory regions
-        let mut regions = Vec::<MemoryRegion>::new();
-        let mut offset = 0;
+    /// Same as `new`, but the memory is backed by huge pages of `page_size`, and the size of each
+    /// region must be a multiple of it.
+    ///
+    /// This fails with `MemoryMappingFailed` if the host doesn't have enough huge pages of that
+    /// size reserved.
+    pub fn new_hugetlb(
+        ranges: &[(GuestAddress, u64)],
+        page_size: HugePageSize,
+    ) -> Result<GuestMemory> {
+        GuestMemory::create(ranges, false, Some(page_size))
+    }
 
-        for range in ranges {
-            if let Some(last) = regions.last() {
-                if last
-                    .guest_base
-                    .checked_add(last.mapping.size() as u64)
-                    .map_or(true, |a| a > range.0)
-                {
-                    return Err(Error::MemoryRegionOverlap);
-                }
-            }
+    /// Same as `new`, but also keeps track of the pages that get written to in each region.
+    ///
+    /// Pages are marked as dirty by all the methods that write to guest memory. The pages covered
+    /// by slices and references returned from `get_slice_at_addr` and `get_ref_at_addr` are marked
+    /// when they're returned, so they must not be held across calls to `get_and_clear_dirty_bitmap`.
+    /// Writes through pointers obtained from `get_host_address` are not tracked.
+    pub fn new_with_dirty_tracking(ranges: &[(GuestAddress, u64)]) -> Result<GuestMemory> {
+        GuestMemory::create(ranges, true, None)
+    }
 
-            let size =
-                usize::try_from(range.1).map_err(|_| Error::MemoryRegionTooLarge(range.1))?;
-            let mapping = MemoryMapping::from_fd_offset(&memfd, size, offset)
-                .map_err(Error::MemoryMappingFailed)?;
+    fn create(
+        ranges: &[(GuestAddress, u64)],
+        track_dirty: bool,
+        huge_page_size: Option<HugePageSize>,
+    ) -> Result<GuestMemory> {
+        let mut builder = GuestMemoryBuilder::new().dirty_tracking(track_dirty);
+        if let Some(page_size) = huge_page_size {
+            builder = builder.huge_page_size(page_size);
+        }
+        for &(guest_base, size) in ranges {
+            builder = builder.region(MemoryRegionOptions::new(guest_base, size));
+        }
+        builder.build()
+    }
+
+    /// Creates a `GuestMemory` whose regions map existing ranges of `memfd`, such as one that was
+    /// shared by another process. Each region is given as its guest address, size, offset in the
+    /// memfd and protection, sorted by guest address. Regions that are hotplugged later are
+    /// allocated past the current end of the memfd, and the ranges of these regions are never
+    /// released or reused, since they belong to the other process.
+    pub(crate) fn from_memfd(
+        memfd: SharedMemory,
+        huge_page_size: Option<HugePageSize>,
+        ranges: &[(GuestAddress, u64, u64, Protection)],
+    ) -> Result<GuestMemory> {
+        let alignment = GuestMemory::alignment(huge_page_size);
+        let memfd = Arc::new(memfd);
+        let mut regions = Vec::<MemoryRegion>::with_capacity(ranges.len());
+        for &(guest_base, size, memfd_offset, prot) in ranges {
+            if size % alignment != 0 || memfd_offset % alignment != 0 {
+                return Err(Error::MemoryNotAligned);
+            }
+            if !matches!(memfd_offset.checked_add(size), Some(end) if end <= memfd.size()) {
+                return Err(Error::RegionOutsideMemfd {
+                    offset: memfd_offset,
+                    size,
+                });
+            }
+            guest_base
+                .checked_add(size)
+                .ok_or(Error::MemoryRegionTooLarge(size))?;
+            if matches!(regions.last(), Some(last) if last.end() > guest_base) {
+                return Err(Error::MemoryRegionOverlap);
+            }
+            let map_size = usize::try_from(size).map_err(|_| Error::MemoryRegionTooLarge(size))?;
+            let mapping = MemoryMapping::new_guarded_flags(
+                map_size,
+                0,
+                alignment as usize,
+                libc::MAP_SHARED,
+                Some((&*memfd, memfd_offset)),
+                prot,
+            )
+            .map_err(Error::MemoryMappingFailed)?;
             regions.push(MemoryRegion {
-                mapping,
-                guest_base: range.0,
-                memfd_offset: offset,
+                mapping: Arc::new(mapping),
+                guest_base,
+                memfd_offset,
+                _memfd_range: None,
+                backing: RegionBacking::SharedMemfd,
+                prot,
+                name: None,
+                dirty: None,
             });
+        }
+
+        let memfd_size = memfd.size();
+        Ok(GuestMemory {
+            regions: Arc::new(regions),
+            mmio: Arc::new(Vec::new()),
+            memfd,
+            memfd_space: Arc::new(Mutex::new(MemfdSpace::new(memfd_size))),
+            track_dirty: false,
+            huge_page_size,
+            guard_size: 0,
+            last_region: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns a new `GuestMemory` with an additional region of `size` bytes at `guest_base`.
+    ///
+    /// The new region is backed by a range of the memfd that was released by a removed region, or
+    /// by a newly allocated range at the end of it, so it shows up in `with_regions` and
+    /// `offset_from_base` like the initial regions. Its contents are zeroed either way. All the existing regions
+    /// keep their host addresses, and `self` is left unchanged, so accesses that are in flight
+    /// against it can complete safely.
+    ///
+    /// Returns `HotplugNotEnabled` unless the memory was built with `GuestMemoryBuilder::hotplug`,
+    /// since the memfd is otherwise sealed against growing.
+    pub fn insert_region(&self, guest_base: GuestAddress, size: u64) -> Result<GuestMemory> {
+        if size & (GuestMemory::alignment(self.huge_page_size) - 1) != 0 {
+            return Err(Error::MemoryNotAligned);
+        }
+        let map_size = usize::try_from(size).map_err(|_| Error::MemoryRegionTooLarge(size))?;
+        let end = guest_base
+            .checked_add(size)
+            .ok_or(Error::MemoryRegionTooLarge(size))?;
+
+        let index = self.insertion_index(guest_base, end)?;
+        if matches!(self.memfd.get_seals(), Ok(seals) if seals.grow_seal()) {
+            return Err(Error::HotplugNotEnabled);
+        }
+
+        // Hold the lock until the new range is mapped, so concurrent hotplug operations on
+        // `GuestMemory` instances sharing this memfd can't hand out the same range twice.
+        let mut space = self
+            .memfd_space
+            .lock()
+            .map_err(|_| Error::HotplugLockPoisoned)?;
+        let free_index = space.find_free(size);
+        let (memfd_offset, memfd) = match free_index {
+            Some(index) => (space.free[index].0, self.memfd.clone()),
+            None => {
+                let memfd_offset = space.size;
+                let new_memfd_size = memfd_offset
+                    .checked_add(size)
+                    .ok_or(Error::MemoryRegionTooLarge(size))?;
+                let mut memfd = self
+                    .memfd
+                    .try_clone()
+                    .map_err(Error::MemoryCreationFailed)?;
+                memfd
+                    .set_size(new_memfd_size)
+                    .map_err(Error::MemorySetSizeFailed)?;
+                (memfd_offset, Arc::new(memfd))
+            }
+        };
+        let mapping = MemoryMapping::new_guarded_flags(
+            map_size,
+            self.guard_size,
+            GuestMemory::alignment(self.huge_page_size) as usize,
+            libc::MAP_SHARED,
+            Some((&*memfd, memfd_offset)),
+            Protection::read_write(),
+        )
+        .map_err(Error::MemoryMappingFailed)?;
+        match free_index {
+            Some(index) => space.take_free(index, size),
+            None => space.size = memfd_offset + size,
+        }
+
+        let mut regions = Vec::with_capacity(self.regions.len() + 1);
+        regions.extend_from_slice(&self.regions[..index]);
+        regions.push(MemoryRegion {
+            mapping: Arc::new(mapping),
+            guest_base,
+            memfd_offset,
+            _memfd_range: MemfdRange::new(&memfd, &self.memfd_space, memfd_offset, size),
+            backing: RegionBacking::SharedMemfd,
+            prot: Protection::read_write(),
+            name: None,
+            dirty: GuestMemory::create_bitmap(map_size, self.track_dirty),
+        });
+        regions.extend_from_slice(&self.regions[index..]);
 
-            offset += size as u64;
+        Ok(GuestMemory {
+            regions: Arc::new(regions),
+            mmio: self.mmio.clone(),
+            memfd,
+            memfd_space: self.memfd_space.clone(),
+            track_dirty: self.track_dirty,
+            huge_page_size: self.huge_page_size,
+            guard_size: self.guard_size,
+            last_region: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns a new `GuestMemory` with an additional region of `size` bytes at `guest_base`, which
+    /// maps `file` starting at `file_offset`, such as a virtio-pmem image or a memfd that was
+    /// created by another process.
+    ///
+    /// The mapping is shared, so writes to the region are carried through to the file. The region
+    /// has no guard pages and isn't tracked by `offset_from_base`; use `region_fd` and
+    /// `offset_from_region_fd` to locate it in `file` instead. `self` is left unchanged.
+    pub fn insert_file_region(
+        &self,
+        guest_base: GuestAddress,
+        size: u64,
+        file: Arc<File>,
+        file_offset: u64,
+        prot: Protection,
+    ) -> Result<GuestMemory> {
+        let page_mask = pagesize() as u64 - 1;
+        if size & page_mask != 0 || file_offset & page_mask != 0 {
+            return Err(Error::MemoryNotAligned);
+        }
+        let map_size = usize::try_from(size).map_err(|_| Error::MemoryRegionTooLarge(size))?;
+        let end = guest_base
+            .checked_add(size)
+            .ok_or(Error::MemoryRegionTooLarge(size))?;
+        let index = self.insertion_index(guest_base, end)?;
+        check_file_range(&file, file_offset, size)?;
+
+        let mapping = MemoryMapping::new_guarded_flags(
+            map_size,
+            0,
+            pagesize(),
+            libc::MAP_SHARED,
+            Some((file.as_ref(), file_offset)),
+            prot,
+        )
+        .map_err(Error::MemoryMappingFailed)?;
+
+        let mut regions = Vec::with_capacity(self.regions.len() + 1);
+        regions.extend_from_slice(&self.regions[..index]);
+        regions.push(MemoryRegion {
+            mapping: Arc::new(mapping),
+            guest_base,
+            memfd_offset: 0,
+            _memfd_range: None,
+            backing: RegionBacking::File(file, file_offset),
+            prot,
+            name: None,
+            dirty: GuestMemory::create_bitmap(map_size, self.track_dirty),
+        });
+        regions.extend_from_slice(&self.regions[index..]);
+
+        Ok(GuestMemory {
+            regions: Arc::new(regions),
+            mmio: self.mmio.clone(),
+            memfd: self.memfd.clone(),
+            memfd_space: self.memfd_space.clone(),
+            track_dirty: self.track_dirty,
+            huge_page_size: self.huge_page_size,
+            guard_size: self.guard_size,
+            last_region: AtomicUsize::new(0),
+        })
+    }
+
+    // Returns where a region spanning `guest_base` to `end` goes in the sorted list of regions, or
+    // `MemoryRegionOverlap` if it overlaps any of the existing memory or MMIO regions.
+    fn insertion_index(&self, guest_base: GuestAddress, end: GuestAddress) -> Result<usize> {
+        let index = match self
+            .regions
+            .binary_search_by(|region| region.start().cmp(&guest_base))
+        {
+            Ok(_) => return Err(Error::MemoryRegionOverlap),
+            Err(index) => index,
+        };
+        if index > 0 && self.regions[index - 1].end() > guest_base {
+            return Err(Error::MemoryRegionOverlap);
+        }
+        if let Some(next) = self.regions.get(index) {
+            if next.start() < end {
+                return Err(Error::MemoryRegionOverlap);
+            }
+        }
+        if self.mmio_overlap(guest_base, end) {
+            return Err(Error::MemoryRegionOverlap);
         }
+        Ok(index)
+    }
+
+    /// Returns a new `GuestMemory` without the region that starts at `guest_base`.
+    ///
+    /// The region stays mapped until every `GuestMemory` that contains it is dropped, so `self`
+    /// remains fully usable and accesses that are in flight against it can complete safely. After
+    /// that, the pages of the memfd range that backs the region are released, and the range is
+    /// reused by later calls to `insert_region`, so other processes that map the memfd must have
+    /// stopped using it by then.
+    pub fn remove_region(&self, guest_base: GuestAddress) -> Result<GuestMemory> {
+        let index = self
+            .regions
+            .binary_search_by(|region| region.start().cmp(&guest_base))
+            .map_err(|_| Error::InvalidGuestAddress(guest_base))?;
+
+        let mut regions = self.regions.as_ref().clone();
+        regions.remove(index);
 
         Ok(GuestMemory {
             regions: Arc::new(regions),
-            memfd: Arc::new(memfd),
+            mmio: self.mmio.clone(),
+            memfd: self.memfd.clone(),
+            memfd_space: self.memfd_space.clone(),
+            track_dirty: self.track_dirty,
+            huge_page_size: self.huge_page_size,
+            guard_size: self.guard_size,
+            last_region: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns a new `GuestMemory` with an MMIO region of `size` bytes at `guest_base`, whose
+    /// accesses through `read_at_addr` and `write_at_addr` are forwarded to `handler`.
+    ///
+    /// MMIO regions have no host memory, so the methods that need it, like `get_slice_at_addr` and
+    /// `get_host_address`, return `MmioRegion` for their addresses. They aren't reported by
+    /// `with_regions` and don't count towards `memory_size`. `self` is left unchanged.
+    pub fn insert_mmio_region(
+        &self,
+        guest_base: GuestAddress,
+        size: u64,
+        handler: Arc<dyn MmioHandler>,
+    ) -> Result<GuestMemory> {
+        let end = guest_base
+            .checked_add(size)
+            .ok_or(Error::MemoryRegionTooLarge(size))?;
+        if size == 0 || self.range_overlap(guest_base, end) || self.mmio_overlap(guest_base, end) {
+            return Err(Error::MemoryRegionOverlap);
+        }
+
+        let mut mmio = self.mmio.as_ref().clone();
+        let index = mmio
+            .binary_search_by(|region| region.guest_base.cmp(&guest_base))
+            .unwrap_or_else(|index| index);
+        mmio.insert(
+            index,
+            MmioRegion {
+                guest_base,
+                size,
+                handler,
+            },
+        );
+
+        Ok(GuestMemory {
+            mmio: Arc::new(mmio),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a new `GuestMemory` without the MMIO region that starts at `guest_base`.
+    pub fn remove_mmio_region(&self, guest_base: GuestAddress) -> Result<GuestMemory> {
+        let index = self
+            .mmio
+            .binary_search_by(|region| region.guest_base.cmp(&guest_base))
+            .map_err(|_| Error::InvalidGuestAddress(guest_base))?;
+
+        let mut mmio = self.mmio.as_ref().clone();
+        mmio.remove(index);
+
+        Ok(GuestMemory {
+            mmio: Arc::new(mmio),
+            ..self.clone()
         })
     }
 
+    fn mmio_overlap(&self, start: GuestAddress, end: GuestAddress) -> bool {
+        self.mmio
+            .iter()
+            .any(|region| region.guest_base < end && start < region.end())
+    }
+
+    fn find_mmio_region(&self, addr: GuestAddress) -> Option<&MmioRegion> {
+        let index = match self
+            .mmio
+            .binary_search_by(|region| region.guest_base.cmp(&addr))
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        Some(&self.mmio[index]).filter(|region| region.contains(addr))
+    }
+
+    // Returns the error for an access to `addr` that isn't backed by any memory region.
+    fn unmapped_error(&self, addr: GuestAddress) -> Error {
+        if self.find_mmio_region(addr).is_some() {
+            Error::MmioRegion(addr)
+        } else {
+            Error::InvalidGuestAddress(addr)
+        }
+    }
+
+    // Returns the size that regions backed by pages of `huge_page_size` must be a multiple of.
+    fn alignment(huge_page_size: Option<HugePageSize>) -> u64 {
+        huge_page_size.map_or(pagesize() as u64, HugePageSize::size)
+    }
+
+    /// Returns the size of the huge pages backing this memory, or `None` if it's backed by normal
+    /// pages.
+    pub fn huge_page_size(&self) -> Option<HugePageSize> {
+        self.huge_page_size
+    }
+
+    fn create_bitmap(size: usize, track_dirty: bool) -> Option<Arc<AtomicBitmap>> {
+        if track_dirty {
+            Some(Arc::new(AtomicBitmap::new(size, pagesize())))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if this `GuestMemory` keeps track of the pages that get written to.
+    pub fn dirty_tracking_enabled(&self) -> bool {
+        self.track_dirty
+    }
+
+    /// Returns the bitmap of the pages written to in the region at `index` since the last call,
+    /// and clears it. Regions are indexed in the same way as in `with_regions`.
+    ///
+    /// Bit `i % 64` of the word at index `i / 64` is set if the `i`th page of the region is dirty.
+    /// Returns `None` if dirty tracking is disabled or if there's no region at `index`.
+    ///
+    /// Pages accessed through `get_slice_at_addr` and `get_ref_at_addr` are marked when the slice
+    /// is handed out, so writes through a slice that is still held across this call aren't
+    /// reported by the next one. Harvest only once such slices have been dropped, e.g. with the
+    /// vCPUs paused.
+    pub fn get_and_clear_dirty_bitmap(&self, index: usize) -> Option<Vec<u64>> {
+        self.regions
+            .get(index)
+            .and_then(|region| region.dirty.as_ref())
+            .map(|dirty| dirty.get_and_reset())
+    }
+
     /// Returns the end address of memory.
     ///
     /// # Examples
@@ -208,8 +869,7 @@ impl GuestMemory {
     /// ```
     pub fn end_addr(&self) -> GuestAddress {
         self.regions
-            .iter()
-            .max_by_key(|region| region.start())
+            .last()
             .map_or(GuestAddress(0), MemoryRegion::end)
     }
 
@@ -223,7 +883,7 @@ impl GuestMemory {
 
     /// Returns true if the given address is within the memory range available to the guest.
     pub fn address_in_range(&self, addr: GuestAddress) -> bool {
-        self.regions.iter().any(|region| region.contains(addr))
+        self.find_region(addr).is_some()
     }
 
     /// Returns true if the given range (start, end) is overlap with the memory range
@@ -250,13 +910,56 @@ impl GuestMemory {
         self.regions.len() as u64
     }
 
+    /// Returns the name of the region at `index`, if it was given one when it was built.
+    /// Regions are indexed in the same way as in `with_regions`.
+    pub fn region_name(&self, index: usize) -> Option<&str> {
+        self.regions
+            .get(index)
+            .and_then(|region| region.name.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Returns the protection the region at `index` is mapped with.
+    pub fn region_protection(&self, index: usize) -> Option<Protection> {
+        self.regions.get(index).map(|region| region.prot)
+    }
+
     /// Madvise away the address range in the host that is associated with the given guest range.
     pub fn remove_range(&self, addr: GuestAddress, count: u64) -> Result<()> {
-        self.do_in_region(addr, move |mapping, offset| {
-            mapping
-                .remove_range(offset, count as usize)
-                .map_err(|e| Error::MemoryAccess(addr, e))
-        })
+        let region = self
+            .find_region(addr)
+            .ok_or_else(|| self.unmapped_error(addr))?;
+        if !region.writable() {
+            return Err(Error::ReadOnlyRegion(addr));
+        }
+        let offset = addr.offset_from(region.start()) as usize;
+        region
+            .mapping
+            .remove_range(offset, count as usize)
+            .map_err(|e| Error::MemoryAccess(addr, e))?;
+        // The removed pages read as zeroes from now on, which counts as a change of contents.
+        region.mark_dirty(offset, count as usize);
+        Ok(())
+    }
+
+    /// Gives the kernel `advice` about the host memory that backs the `count` bytes of guest memory
+    /// starting at `addr`, which may span multiple adjacent regions.
+    ///
+    /// `addr` must be page aligned. Advice that discards contents, like `Advice::DontNeed`, isn't
+    /// allowed for read-only regions, and marks the range as dirty.
+    pub fn advise(&self, addr: GuestAddress, count: u64, advice: Advice) -> Result<()> {
+        let count = usize::try_from(count).map_err(|_| Error::MemoryRegionTooLarge(count))?;
+        self.do_in_regions_exact(
+            addr,
+            count,
+            advice.discards_contents(),
+            |mapping, offset, done, len| {
+                mapping
+                    .advise(offset, len, advice)
+                    .map_err(|e| Error::MemoryAccess(addr.unchecked_add(done as u64), e))?;
+                Ok(len)
+            },
+        )
     }
 
     /// Perform the specified action on each region's addresses.
@@ -267,11 +970,18 @@ impl GuestMemory {
     ///  * size: usize
     ///  * host_addr: usize
     ///  * memfd_offset: usize
+    ///
+    /// Only the regions that are backed by the shared memfd are reported, so `memfd_offset` is
+    /// always an offset in the fd returned by `as_raw_fd`. The indices still count the other
+    /// regions, whose fds and offsets are returned by `region_fd` and `offset_from_region_fd`.
     pub fn with_regions<F, E>(&self, mut cb: F) -> result::Result<(), E>
     where
         F: FnMut(usize, GuestAddress, usize, usize, u64) -> result::Result<(), E>,
     {
         for (index, region) in self.regions.iter().enumerate() {
+            if !matches!(region.backing, RegionBacking::SharedMemfd) {
+                continue;
+            }
             cb(
                 index,
                 region.start(),
@@ -285,8 +995,14 @@ impl GuestMemory {
 
     /// Writes a slice to guest memory at the specified guest address.
     /// Returns the number of bytes written.  The number of bytes written can
-    /// be less than the length of the slice if there isn't enough room in the
-    /// memory region.
+    /// be less than the length of the slice if the write runs into a guest
+    /// address that isn't backed by any memory region, or that is backed by a
+    /// read-only region. Writes that span multiple adjacent regions are split
+    /// between them. Returns `ReadOnlyRegion` if `guest_addr` itself is in a
+    /// read-only region.
+    ///
+    /// Writes that start in an MMIO region are forwarded to its handler, and
+    /// stop at the end of the region.
     ///
     /// # Examples
     /// * Write a slice at guestaddress 0x200.
@@ -302,19 +1018,24 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_at_addr(&self, buf: &[u8], guest_addr: GuestAddress) -> Result<usize> {
-        self.do_in_region(guest_addr, move |mapping, offset| {
+        if let Some(mmio) = self.find_mmio_region(guest_addr) {
+            let (offset, len) = mmio.access_range(guest_addr, buf.len());
+            mmio.handler.write(offset, &buf[..len]);
+            return Ok(len);
+        }
+        self.write_in_regions(guest_addr, buf.len(), |mapping, offset, done, len| {
             mapping
-                .write_slice(buf, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .write_slice(&buf[done..done + len], offset)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
         })
     }
 
     /// Writes the entire contents of a slice to guest memory at the specified
     /// guest address.
     ///
-    /// Returns an error if there isn't enough room in the memory region to
-    /// complete the entire write. Part of the data may have been written
-    /// nevertheless.
+    /// Returns an error if the write runs into a guest address that isn't
+    /// backed by any memory region before it completes. Part of the data may
+    /// have been written nevertheless.
     ///
     /// # Examples
     ///
@@ -342,8 +1063,12 @@ impl GuestMemory {
 
     /// Reads to a slice from guest memory at the specified guest address.
     /// Returns the number of bytes read.  The number of bytes read can
-    /// be less than the length of the slice if there isn't enough room in the
-    /// memory region.
+    /// be less than the length of the slice if the read runs into a guest
+    /// address that isn't backed by any memory region. Reads that span
+    /// multiple adjacent regions are split between them.
+    ///
+    /// Reads that start in an MMIO region are forwarded to its handler, and
+    /// stop at the end of the region.
     ///
     /// # Examples
     /// * Read a slice of length 16 at guestaddress 0x200.
@@ -360,18 +1085,24 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn read_at_addr(&self, buf: &mut [u8], guest_addr: GuestAddress) -> Result<usize> {
-        self.do_in_region(guest_addr, move |mapping, offset| {
+        if let Some(mmio) = self.find_mmio_region(guest_addr) {
+            let (offset, len) = mmio.access_range(guest_addr, buf.len());
+            mmio.handler.read(offset, &mut buf[..len]);
+            return Ok(len);
+        }
+        self.do_in_regions(guest_addr, buf.len(), |mapping, offset, done, len| {
             mapping
-                .read_slice(buf, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .read_slice(&mut buf[done..done + len], offset)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
         })
     }
 
     /// Reads from guest memory at the specified address to fill the entire
     /// buffer.
     ///
-    /// Returns an error if there isn't enough room in the memory region to fill
-    /// the entire buffer. Part of the buffer may have been filled nevertheless.
+    /// Returns an error if the read runs into a guest address that isn't backed
+    /// by any memory region before the buffer is filled. Part of the buffer may
+    /// have been filled nevertheless.
     ///
     /// # Examples
     ///
@@ -403,6 +1134,9 @@ impl GuestMemory {
     /// mid-read.  However, as long as the type T is plain old data and can
     /// handle random initialization, everything will be OK.
     ///
+    /// The object may straddle the boundary between two adjacent regions. If
+    /// part of it falls into a hole, the address of the hole is reported.
+    ///
     /// # Examples
     /// * Read a u64 from two areas of guest memory backed by separate mappings.
     ///
@@ -419,16 +1153,29 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn read_obj_from_addr<T: DataInit>(&self, guest_addr: GuestAddress) -> Result<T> {
-        self.do_in_region(guest_addr, |mapping, offset| {
-            mapping
-                .read_obj(offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
-        })
+        // Safe because `DataInit` types can be initialized from any combination of bytes.
+        let mut val: T = unsafe { zeroed() };
+        let buf = val.as_mut_slice();
+        self.do_in_regions_exact(
+            guest_addr,
+            buf.len(),
+            false,
+            |mapping, offset, done, len| {
+                mapping
+                    .read_slice(&mut buf[done..done + len], offset)
+                    .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
+            },
+        )?;
+        Ok(val)
     }
 
     /// Writes an object to the memory region at the specified guest address.
     /// Returns Ok(()) if the object fits, or Err if it extends past the end.
     ///
+    /// The object may straddle the boundary between two adjacent regions. If
+    /// part of it falls into a hole, the address of the hole is reported and
+    /// the part preceding the hole may have been written nevertheless.
+    ///
     /// # Examples
     /// * Write a u64 at guest address 0x1100.
     ///
@@ -442,16 +1189,21 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_obj_at_addr<T: DataInit>(&self, val: T, guest_addr: GuestAddress) -> Result<()> {
-        self.do_in_region(guest_addr, move |mapping, offset| {
+        let buf = val.as_slice();
+        self.do_in_regions_exact(guest_addr, buf.len(), true, |mapping, offset, done, len| {
             mapping
-                .write_obj(val, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .write_slice(&buf[done..done + len], offset)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
         })
     }
 
     /// Returns a `VolatileSlice` of `len` bytes starting at `addr`. Returns an error if the slice
     /// is not a subset of this `GuestMemory`.
     ///
+    /// If dirty tracking is enabled, the pages of the slice are marked as dirty up front, even if
+    /// the slice is only read from. See `get_and_clear_dirty_bitmap` for how this interacts with
+    /// harvesting the bitmap.
+    ///
     /// # Examples
     /// * Write `99` to 30 bytes starting at guest address 0x1010.
     ///
@@ -466,23 +1218,33 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn get_slice_at_addr(&self, addr: GuestAddress, len: usize) -> Result<VolatileSlice> {
-        self.regions
-            .iter()
-            .find(|region| region.contains(addr))
-            .ok_or(Error::InvalidGuestAddress(addr))
+        self.find_region(addr)
+            .ok_or_else(|| self.unmapped_error(addr))
             .and_then(|region| {
+                // Slices can always be written to, so they aren't handed out for read-only regions.
+                if !region.writable() {
+                    return Err(Error::ReadOnlyRegion(addr));
+                }
                 // The cast to a usize is safe here because we know that `region.contains(addr)` and
                 // it's not possible for a memory region to be larger than what fits in a usize.
-                region
+                let offset = addr.offset_from(region.start()) as usize;
+                let slice = region
                     .mapping
-                    .get_slice(addr.offset_from(region.start()) as usize, len)
-                    .map_err(Error::VolatileMemoryAccess)
+                    .get_slice(offset, len)
+                    .map_err(Error::VolatileMemoryAccess)?;
+                // We can't tell whether the slice will be written to, or when, so assume it will
+                // be, and mark the pages now. Unlike the copying accessors, this can't wait until
+                // after the write.
+                region.mark_dirty(offset, len);
+                Ok(slice)
             })
     }
 
     /// Returns a `VolatileRef` to an object at `addr`. Returns Ok(()) if the object fits, or Err if
     /// it extends past the end.
     ///
+    /// Like `get_slice_at_addr`, this marks the object's pages as dirty even if it's only loaded.
+    ///
     /// # Examples
     /// * Get a &u64 at offset 0x1010.
     ///
@@ -511,6 +1273,9 @@ impl GuestMemory {
     /// * `src` - Read from `src` to memory.
     /// * `count` - Read `count` bytes from `src` to memory.
     ///
+    /// The range may span multiple adjacent regions. An error is returned if
+    /// it runs into a guest address that isn't backed by any memory region.
+    ///
     /// # Examples
     ///
     /// * Read bytes from /dev/urandom
@@ -536,10 +1301,11 @@ impl GuestMemory {
         src: &dyn AsRawFd,
         count: usize,
     ) -> Result<()> {
-        self.do_in_region(guest_addr, move |mapping, offset| {
+        self.do_in_regions_exact(guest_addr, count, true, |mapping, offset, done, len| {
             mapping
-                .read_to_memory(offset, src, count)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .read_to_memory(offset, src, len)
+                .map(|_| len)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
         })
     }
 
@@ -550,6 +1316,9 @@ impl GuestMemory {
     /// * `dst` - Write from memory to `dst`.
     /// * `count` - Read `count` bytes from memory to `src`.
     ///
+    /// The range may span multiple adjacent regions. An error is returned if
+    /// it runs into a guest address that isn't backed by any memory region.
+    ///
     /// # Examples
     ///
     /// * Write 128 bytes to /dev/null
@@ -573,49 +1342,376 @@ impl GuestMemory {
         dst: &dyn AsRawFd,
         count: usize,
     ) -> Result<()> {
-        self.do_in_region(guest_addr, move |mapping, offset| {
+        self.do_in_regions_exact(guest_addr, count, false, |mapping, offset, done, len| {
             mapping
-                .write_from_memory(offset, dst, count)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .write_from_memory(offset, dst, len)
+                .map(|_| len)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
         })
     }
 
-    /// Convert a GuestAddress into a pointer in the address space of this
-    /// process. This should only be necessary for giving addresses to the
-    /// kernel, as with vhost ioctls. Normal reads/writes to guest memory should
-    /// be done through `write_from_memory`, `read_obj_from_addr`, etc.
+    /// Same as `read_to_memory`, but reads from `src` starting at `file_offset`, without using
+    /// or changing its file position. Multiple threads can use this to read from the same file
+    /// concurrently.
     ///
     /// # Arguments
-    /// * `guest_addr` - Guest address to convert.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// # use sys_util::{GuestAddress, GuestMemory};
-    /// # fn test_host_addr() -> Result<(), ()> {
-    ///     let start_addr = GuestAddress(0x1000);
-    ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x500)]).map_err(|_| ())?;
-    ///     let addr = gm.get_host_address(GuestAddress(0x1200)).unwrap();
-    ///     println!("Host address is {:p}", addr);
-    ///     Ok(())
-    /// # }
-    /// ```
-    pub fn get_host_address(&self, guest_addr: GuestAddress) -> Result<*const u8> {
-        self.do_in_region(guest_addr, |mapping, offset| {
-            // This is safe; `do_in_region` already checks that offset is in
-            // bounds.
-            Ok(unsafe { mapping.as_ptr().add(offset) } as *const u8)
+    /// * `guest_addr` - Begin writing memory at this offset.
+    /// * `src` - Read from `src` to memory.
+    /// * `file_offset` - Begin reading `src` at this offset.
+    /// * `count` - Read `count` bytes from `src` to memory.
+    pub fn read_to_memory_at(
+        &self,
+        guest_addr: GuestAddress,
+        src: &dyn AsRawFd,
+        file_offset: u64,
+        count: usize,
+    ) -> Result<()> {
+        self.do_in_regions_exact(guest_addr, count, true, |mapping, offset, done, len| {
+            file_offset
+                .checked_add(done as u64)
+                .ok_or(mmap::Error::InvalidOffset)
+                .and_then(|file_offset| mapping.read_to_memory_at(offset, src, file_offset, len))
+                .map(|_| len)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
         })
     }
 
-    pub fn do_in_region<F, T>(&self, guest_addr: GuestAddress, cb: F) -> Result<T>
-    where
-        F: FnOnce(&MemoryMapping, usize) -> Result<T>,
-    {
-        self.regions
-            .iter()
-            .find(|region| region.contains(guest_addr))
-            .ok_or(Error::InvalidGuestAddress(guest_addr))
+    /// Same as `write_from_memory`, but writes to `dst` starting at `file_offset`, without using
+    /// or changing its file position. Multiple threads can use this to write to the same file
+    /// concurrently.
+    ///
+    /// # Arguments
+    /// * `guest_addr` - Begin reading memory from this offset.
+    /// * `dst` - Write from memory to `dst`.
+    /// * `file_offset` - Begin writing `dst` at this offset.
+    /// * `count` - Read `count` bytes from memory to `dst`.
+    pub fn write_from_memory_at(
+        &self,
+        guest_addr: GuestAddress,
+        dst: &dyn AsRawFd,
+        file_offset: u64,
+        count: usize,
+    ) -> Result<()> {
+        self.do_in_regions_exact(guest_addr, count, false, |mapping, offset, done, len| {
+            file_offset
+                .checked_add(done as u64)
+                .ok_or(mmap::Error::InvalidOffset)
+                .and_then(|file_offset| mapping.write_from_memory_at(offset, dst, file_offset, len))
+                .map(|_| len)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as u64), e))
+        })
+    }
+
+    /// Copies `count` bytes of `src`, starting at `file_offset`, to guest memory at `guest_addr`.
+    ///
+    /// Parts of the range that are backed by the memfd or a file are copied in the kernel, without
+    /// staging the data in a user space buffer: with `copy_file_range` if `src` is a regular file,
+    /// or with `splice` if it's a pipe or a socket. If that isn't supported for a pair of files, or
+    /// the range is backed by anonymous memory, this falls back to `read_to_memory_at`. Either way,
+    /// the file position of a regular file `src` isn't used or changed.
+    ///
+    /// Pipes and sockets are read from in order instead, so `file_offset` is ignored for them, and
+    /// the fallback is `read_to_memory`.
+    ///
+    /// The range may span multiple adjacent regions. An error is returned if it runs into a guest
+    /// address that isn't backed by any memory region, or if the end of `src` is reached first.
+    pub fn copy_from_file(
+        &self,
+        guest_addr: GuestAddress,
+        src: &dyn AsRawFd,
+        file_offset: u64,
+        count: usize,
+    ) -> Result<()> {
+        self.do_in_regions_exact(guest_addr, count, true, |mapping, offset, done, len| {
+            let addr = guest_addr.unchecked_add(done as u64);
+            let file_offset = file_offset
+                .checked_add(done as u64)
+                .ok_or(Error::MemoryAccess(addr, mmap::Error::InvalidOffset))?;
+            let mut copied = 0;
+            if let Some((fd, fd_offset)) = self.backing_fd(addr) {
+                copied = kernel_copy(src, file_offset, fd, fd_offset + offset as u64, len)
+                    .map_err(|e| Error::MemoryAccess(addr, mmap::Error::ReadToMemory(e)))?;
+            }
+            if copied < len && is_stream(src) {
+                mapping
+                    .read_to_memory(offset + copied, src, len - copied)
+                    .map_err(|e| Error::MemoryAccess(addr, e))?;
+            } else if copied < len {
+                mapping
+                    .read_to_memory_at(
+                        offset + copied,
+                        src,
+                        file_offset + copied as u64,
+                        len - copied,
+                    )
+                    .map_err(|e| Error::MemoryAccess(addr, e))?;
+            }
+            Ok(len)
+        })
+    }
+
+    /// Copies `count` bytes of guest memory at `guest_addr` to `dst`, starting at `file_offset`.
+    ///
+    /// This is the counterpart of `copy_from_file`, and falls back to `write_from_memory_at` in the
+    /// same cases. Pipes and sockets are written to in order, with `write_from_memory`.
+    pub fn copy_to_file(
+        &self,
+        guest_addr: GuestAddress,
+        dst: &dyn AsRawFd,
+        file_offset: u64,
+        count: usize,
+    ) -> Result<()> {
+        self.do_in_regions_exact(guest_addr, count, false, |mapping, offset, done, len| {
+            let addr = guest_addr.unchecked_add(done as u64);
+            let file_offset = file_offset
+                .checked_add(done as u64)
+                .ok_or(Error::MemoryAccess(addr, mmap::Error::InvalidOffset))?;
+            let mut copied = 0;
+            if let Some((fd, fd_offset)) = self.backing_fd(addr) {
+                copied = kernel_copy(fd, fd_offset + offset as u64, dst, file_offset, len)
+                    .map_err(|e| Error::MemoryAccess(addr, mmap::Error::WriteFromMemory(e)))?;
+            }
+            if copied < len && is_stream(dst) {
+                mapping
+                    .write_from_memory(offset + copied, dst, len - copied)
+                    .map_err(|e| Error::MemoryAccess(addr, e))?;
+            } else if copied < len {
+                mapping
+                    .write_from_memory_at(
+                        offset + copied,
+                        dst,
+                        file_offset + copied as u64,
+                        len - copied,
+                    )
+                    .map_err(|e| Error::MemoryAccess(addr, e))?;
+            }
+            Ok(len)
+        })
+    }
+
+    // Returns the file that backs the region containing `addr`, and the offset of the start of the
+    // region in it, or `None` if the region is backed by anonymous memory.
+    fn backing_fd(&self, addr: GuestAddress) -> Option<(&dyn AsRawFd, u64)> {
+        self.find_region(addr)?.fd_offset(&self.memfd)
+    }
+
+    /// Reads from `src`, starting at `file_offset`, into the guest memory described by `descs`
+    /// with `preadv`, in order. Each descriptor is a `(GuestAddress, len)` pair, and may span
+    /// multiple adjacent regions. The file position of `src` isn't used or changed.
+    ///
+    /// Short transfers are retried until all the descriptors are filled, and the total number of
+    /// bytes read is returned. It is only less than the combined length of the descriptors if the
+    /// end of the file is reached. All the descriptors are validated before anything is read, and
+    /// are marked as dirty up front, like the slices returned by `get_slice_at_addr`.
+    pub fn read_vectored_from_fd(
+        &self,
+        src: &dyn AsRawFd,
+        file_offset: u64,
+        descs: &[(GuestAddress, usize)],
+    ) -> Result<usize> {
+        self.vectored_io(descs, file_offset, true, |iovecs, offset| {
+            // This is safe because the iovecs all point to guest memory owned by `self`, and the
+            // kernel only writes to the memory that they describe.
+            unsafe {
+                libc::preadv(
+                    src.as_raw_fd(),
+                    iovecs.as_ptr(),
+                    iovecs.len() as c_int,
+                    offset,
+                )
+            }
+        })
+    }
+
+    /// Writes the guest memory described by `descs` to `dst`, starting at `file_offset`, with
+    /// `pwritev`, in order. Each descriptor is a `(GuestAddress, len)` pair, and may span multiple
+    /// adjacent regions. The file position of `dst` isn't used or changed.
+    ///
+    /// Short transfers are retried until all the descriptors are written, and the total number of
+    /// bytes written is returned. It is only less than the combined length of the descriptors if
+    /// `dst` doesn't accept any more data. All the descriptors are validated before anything is
+    /// written.
+    pub fn write_vectored_to_fd(
+        &self,
+        dst: &dyn AsRawFd,
+        file_offset: u64,
+        descs: &[(GuestAddress, usize)],
+    ) -> Result<usize> {
+        self.vectored_io(descs, file_offset, false, |iovecs, offset| {
+            // This is safe because the iovecs all point to guest memory owned by `self`, and the
+            // kernel only reads from the memory that they describe.
+            unsafe {
+                libc::pwritev(
+                    dst.as_raw_fd(),
+                    iovecs.as_ptr(),
+                    iovecs.len() as c_int,
+                    offset,
+                )
+            }
+        })
+    }
+
+    // Calls `io` with iovecs covering the guest memory described by `descs` and the file offset to
+    // transfer them at, until all of it is transferred or `io` returns 0. `to_memory` is true if
+    // `io` writes to the guest memory.
+    fn vectored_io<F>(
+        &self,
+        descs: &[(GuestAddress, usize)],
+        file_offset: u64,
+        to_memory: bool,
+        mut io: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&[iovec], off_t) -> isize,
+    {
+        let mut addrs = Vec::with_capacity(descs.len());
+        let mut slices = Vec::with_capacity(descs.len());
+        for &(addr, len) in descs {
+            self.do_in_regions_exact(addr, len, to_memory, |mapping, offset, done, len| {
+                addrs.push(addr.unchecked_add(done as u64));
+                // This is safe because the range was checked to be within the mapping, which is
+                // kept alive by `self` for as long as the slices are used.
+                slices.push(unsafe {
+                    VolatileSlice::from_raw_parts(mapping.as_ptr().add(offset), len)
+                });
+                Ok(len)
+            })?;
+        }
+
+        let mut first = 0;
+        let mut done = 0;
+        while first < slices.len() {
+            let offset = file_offset
+                .checked_add(done as u64)
+                .filter(|&offset| offset <= off_t::MAX as u64)
+                .ok_or(Error::MemoryAccess(
+                    addrs[first],
+                    mmap::Error::InvalidOffset,
+                ))?;
+            // The kernel rejects requests with more than `UIO_MAXIOV` iovecs.
+            let end = min(slices.len(), first + libc::UIO_MAXIOV as usize);
+            let ret = io(
+                VolatileSlice::as_iovecs(&slices[first..end]),
+                offset as off_t,
+            );
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                let e = if to_memory {
+                    mmap::Error::ReadToMemory(e)
+                } else {
+                    mmap::Error::WriteFromMemory(e)
+                };
+                return Err(Error::MemoryAccess(addrs[first], e));
+            }
+            if ret == 0 {
+                break;
+            }
+
+            // Skip past the slices that were completed, and trim the one that was only partially
+            // completed.
+            let mut completed = ret as usize;
+            done += completed;
+            while completed > 0 {
+                let size = slices[first].size();
+                if completed < size {
+                    slices[first] = slices[first]
+                        .offset(completed)
+                        .map_err(Error::VolatileMemoryAccess)?;
+                    addrs[first] = addrs[first].unchecked_add(completed as u64);
+                    break;
+                }
+                completed -= size;
+                first += 1;
+            }
+        }
+
+        Ok(done)
+    }
+
+    /// Convert a GuestAddress into a pointer in the address space of this
+    /// process. This should only be necessary for giving addresses to the
+    /// kernel, as with vhost ioctls. Normal reads/writes to guest memory should
+    /// be done through `write_from_memory`, `read_obj_from_addr`, etc.
+    ///
+    /// # Arguments
+    /// * `guest_addr` - Guest address to convert.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use sys_util::{GuestAddress, GuestMemory};
+    /// # fn test_host_addr() -> Result<(), ()> {
+    ///     let start_addr = GuestAddress(0x1000);
+    ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x500)]).map_err(|_| ())?;
+    ///     let addr = gm.get_host_address(GuestAddress(0x1200)).unwrap();
+    ///     println!("Host address is {:p}", addr);
+    ///     Ok(())
+    /// # }
+    /// ```
+    pub fn get_host_address(&self, guest_addr: GuestAddress) -> Result<*const u8> {
+        self.do_in_region(guest_addr, |mapping, offset| {
+            // This is safe; `do_in_region` already checks that offset is in
+            // bounds.
+            Ok(unsafe { mapping.as_ptr().add(offset) } as *const u8)
+        })
+    }
+
+    /// Returns the index of the region containing `addr`, if any.
+    fn region_index(&self, addr: GuestAddress) -> Option<usize> {
+        // Consecutive accesses usually land in the same region, so try the last hit first.
+        let last = self.last_region.load(Ordering::Relaxed);
+        if let Some(region) = self.regions.get(last) {
+            if region.contains(addr) {
+                return Some(last);
+            }
+        }
+
+        // Since the regions are sorted and don't overlap, the only candidate is the last region
+        // starting at or below `addr`.
+        let index = match self
+            .regions
+            .binary_search_by(|region| region.start().cmp(&addr))
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        if self.regions[index].contains(addr) {
+            self.last_region.store(index, Ordering::Relaxed);
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the region containing `addr`, if any.
+    pub(crate) fn find_region(&self, addr: GuestAddress) -> Option<&MemoryRegion> {
+        self.region_index(addr).map(|index| &self.regions[index])
+    }
+
+    /// Returns the memory regions, sorted by guest address.
+    pub(crate) fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// Returns the size of the pages that back `region`, which is one of the regions of this
+    /// `GuestMemory`.
+    pub(crate) fn region_page_size(&self, region: &MemoryRegion) -> usize {
+        match region.backing {
+            RegionBacking::SharedMemfd => GuestMemory::alignment(self.huge_page_size) as usize,
+            _ => pagesize(),
+        }
+    }
+
+    pub fn do_in_region<F, T>(&self, guest_addr: GuestAddress, cb: F) -> Result<T>
+    where
+        F: FnOnce(&MemoryMapping, usize) -> Result<T>,
+    {
+        self.find_region(guest_addr)
+            .ok_or_else(|| self.unmapped_error(guest_addr))
             .and_then(|region| {
                 cb(
                     &region.mapping,
@@ -624,6 +1720,118 @@ impl GuestMemory {
             })
     }
 
+    /// Perform the specified action on the `count` bytes starting at `guest_addr`, splitting it
+    /// into one chunk for each of the adjacent regions the range spans.
+    ///
+    /// Callback is called for each chunk with arguments:
+    ///  * mapping: &MemoryMapping
+    ///  * offset: usize - offset of the chunk within `mapping`
+    ///  * done: usize - number of bytes handled by the previous chunks
+    ///  * len: usize - size of the chunk
+    ///
+    /// and returns the number of bytes it handled. Iteration stops when the callback handles fewer
+    /// than `len` bytes, or when the next chunk starts at an address that isn't backed by any
+    /// region. Returns the total number of bytes handled, or an error if `guest_addr` itself isn't
+    /// backed by any region. Errors from the callback should report the address of the failing
+    /// chunk, which is `guest_addr` plus `done`.
+    pub(crate) fn do_in_regions<F>(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+        cb: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&MemoryMapping, usize, usize, usize) -> Result<usize>,
+    {
+        self.do_in_regions_inner(guest_addr, count, false, cb)
+    }
+
+    /// Same as `do_in_regions`, but marks the bytes handled by the callback as dirty, and stops at
+    /// read-only regions.
+    fn write_in_regions<F>(&self, guest_addr: GuestAddress, count: usize, cb: F) -> Result<usize>
+    where
+        F: FnMut(&MemoryMapping, usize, usize, usize) -> Result<usize>,
+    {
+        self.do_in_regions_inner(guest_addr, count, true, cb)
+    }
+
+    fn do_in_regions_inner<F>(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+        write: bool,
+        mut cb: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&MemoryMapping, usize, usize, usize) -> Result<usize>,
+    {
+        let mut done = 0;
+        let mut addr = guest_addr;
+        let mut index = self
+            .region_index(addr)
+            .ok_or_else(|| self.unmapped_error(addr))?;
+
+        loop {
+            let region = &self.regions[index];
+            if write && !region.writable() {
+                if done == 0 {
+                    return Err(Error::ReadOnlyRegion(addr));
+                }
+                break;
+            }
+            // The cast to a usize is safe here because we know that `region.contains(addr)` and
+            // it's not possible for a memory region to be larger than what fits in a usize.
+            let offset = addr.offset_from(region.start()) as usize;
+            let len = min(count - done, region.mapping.size() - offset);
+            let completed = cb(&region.mapping, offset, done, len)?;
+            // Pages are marked after they're written, so that a concurrent harvest of the bitmap
+            // can't clear the bit before the data lands.
+            if write {
+                region.mark_dirty(offset, completed);
+            }
+            done += completed;
+            if completed < len || done == count {
+                break;
+            }
+
+            // Regions are sorted, so the next chunk can only be found in the following region, and
+            // only if it starts exactly where the current one ends.
+            addr = region.end();
+            match self.regions.get(index + 1) {
+                Some(next) if next.start() == addr => index += 1,
+                _ => break,
+            }
+        }
+
+        Ok(done)
+    }
+
+    /// Same as `do_in_regions`, but returns an error with the first address that isn't backed by any
+    /// region if the callbacks don't handle all `count` bytes. If `write` is true, the handled bytes
+    /// are marked as dirty, and running into a read-only region is an error too.
+    fn do_in_regions_exact<F>(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+        write: bool,
+        cb: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&MemoryMapping, usize, usize, usize) -> Result<usize>,
+    {
+        let completed = self.do_in_regions_inner(guest_addr, count, write, cb)?;
+        if completed == count {
+            return Ok(());
+        }
+        // The addition can't overflow because the first `completed` bytes were backed by regions
+        // whose bounds were checked when they were created.
+        let addr = guest_addr.unchecked_add(completed as u64);
+        match self.find_region(addr) {
+            Some(region) if write && !region.writable() => Err(Error::ReadOnlyRegion(addr)),
+            _ => Err(self.unmapped_error(addr)),
+        }
+    }
+
     /// Convert a GuestAddress into an offset within self.memfd.
     ///
     /// Due to potential gaps within GuestMemory, it is helpful to know the
@@ -631,6 +1839,9 @@ impl GuestMemory {
     /// can then be passed to another process mapping the memfd to read data
     /// starting at that address.
     ///
+    /// Returns `NotMemfdBacked` if the address is in a region with another kind of backing; use
+    /// `offset_from_region_fd` for those.
+    ///
     /// # Arguments
     /// * `guest_addr` - Guest address to convert.
     ///
@@ -648,11 +1859,576 @@ impl GuestMemory {
     /// assert_eq!(offset, 0x3500);
     /// ```
     pub fn offset_from_base(&self, guest_addr: GuestAddress) -> Result<u64> {
-        self.regions
-            .iter()
-            .find(|region| region.contains(guest_addr))
-            .ok_or(Error::InvalidGuestAddress(guest_addr))
-            .map(|region| region.memfd_offset + guest_addr.offset_from(region.start()))
+        let region = self
+            .find_region(guest_addr)
+            .ok_or(Error::InvalidGuestAddress(guest_addr))?;
+        match region.backing {
+            RegionBacking::SharedMemfd => {
+                Ok(region.memfd_offset + guest_addr.offset_from(region.start()))
+            }
+            _ => Err(Error::NotMemfdBacked(guest_addr)),
+        }
+    }
+
+    /// Returns the fd of the memfd or file that backs the region containing `guest_addr`. This is
+    /// the per-region equivalent of `as_raw_fd`, and also covers regions that aren't backed by the
+    /// shared memfd.
+    ///
+    /// Returns `AnonymousRegion` if the region is backed by anonymous memory.
+    pub fn region_fd(&self, guest_addr: GuestAddress) -> Result<RawFd> {
+        let region = self
+            .find_region(guest_addr)
+            .ok_or(Error::InvalidGuestAddress(guest_addr))?;
+        region
+            .fd_offset(&self.memfd)
+            .map(|(fd, _)| fd.as_raw_fd())
+            .ok_or(Error::AnonymousRegion(guest_addr))
+    }
+
+    /// Converts `guest_addr` into an offset within the fd returned by `region_fd` for the same
+    /// address. This is the per-region equivalent of `offset_from_base`.
+    ///
+    /// Returns `AnonymousRegion` if the region is backed by anonymous memory.
+    pub fn offset_from_region_fd(&self, guest_addr: GuestAddress) -> Result<u64> {
+        let region = self
+            .find_region(guest_addr)
+            .ok_or(Error::InvalidGuestAddress(guest_addr))?;
+        let (_, offset) = region
+            .fd_offset(&self.memfd)
+            .ok_or(Error::AnonymousRegion(guest_addr))?;
+        Ok(offset + guest_addr.offset_from(region.start()))
+    }
+}
+
+// Checks that `size` bytes at `offset` fit in `file`, so accessing the mapping can't fault past
+// its end. Only regular files are checked, since the size of devices such as DAX-capable pmem
+// isn't reported in their metadata.
+fn check_file_range(file: &File, offset: u64, size: u64) -> Result<()> {
+    let metadata = file.metadata().map_err(Error::FileMetadata)?;
+    if metadata.is_file() && !matches!(offset.checked_add(size), Some(end) if end <= metadata.len())
+    {
+        return Err(Error::RegionOutsideFile { offset, size });
+    }
+    Ok(())
+}
+
+// Returns the file type bits of `st_mode`, and the device, of the file behind `fd`.
+fn file_type(fd: &dyn AsRawFd) -> io::Result<(libc::mode_t, libc::dev_t)> {
+    // Safe because we pass a pointer to a `stat` that lives on the stack, and check the result.
+    let mut st: libc::stat64 = unsafe { zeroed() };
+    if unsafe { libc::fstat64(fd.as_raw_fd(), &mut st) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((st.st_mode & libc::S_IFMT, st.st_dev))
+}
+
+// Returns true if `fd` is a pipe or a socket, which have no file offsets.
+fn is_stream(fd: &dyn AsRawFd) -> bool {
+    matches!(
+        file_type(fd),
+        Ok((libc::S_IFIFO, _)) | Ok((libc::S_IFSOCK, _))
+    )
+}
+
+// Returns `offset` as an `off_t`, if the `len` bytes from there can all be addressed with one.
+fn checked_off_t(offset: u64, len: usize) -> io::Result<off_t> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= off_t::MAX as u64 => Ok(offset as off_t),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file offset doesn't fit in an off_t",
+        )),
+    }
+}
+
+// Copies up to `len` bytes of `src` at `src_offset` to `dst` at `dst_offset` in the kernel, and
+// returns how many bytes were copied. Regular files are copied between with `copy_file_range`.
+// Pipes and sockets are spliced from or to a regular file instead, and their offset is ignored.
+//
+// This stops early at the end of `src`, or if the kernel can't copy between the two files, so the
+// caller can fall back to copying the rest through memory.
+fn kernel_copy(
+    src: &dyn AsRawFd,
+    src_offset: u64,
+    dst: &dyn AsRawFd,
+    dst_offset: u64,
+    len: usize,
+) -> io::Result<usize> {
+    let (src_type, src_dev) = file_type(src)?;
+    let (dst_type, dst_dev) = file_type(dst)?;
+    let stream = |file_type| file_type == libc::S_IFIFO || file_type == libc::S_IFSOCK;
+    match (src_type, dst_type) {
+        (libc::S_IFREG, libc::S_IFREG) => {
+            copy_file_range_all(src, src_offset, dst, dst_offset, len, src_dev != dst_dev)
+        }
+        (src_type, libc::S_IFREG) if stream(src_type) => splice_all(
+            src,
+            None,
+            dst,
+            Some(dst_offset),
+            len,
+            src_type == libc::S_IFIFO,
+        ),
+        (libc::S_IFREG, dst_type) if stream(dst_type) => splice_all(
+            src,
+            Some(src_offset),
+            dst,
+            None,
+            len,
+            dst_type == libc::S_IFIFO,
+        ),
+        // Devices such as /dev/zero can only be copied through memory.
+        _ => Ok(0),
+    }
+}
+
+// Copies up to `len` bytes between two regular files with `copy_file_range`, as for
+// `kernel_copy`. `cross_fs` is true if the files are on different file systems, which some kernels
+// reject with `EINVAL` rather than `EXDEV`.
+fn copy_file_range_all(
+    src: &dyn AsRawFd,
+    src_offset: u64,
+    dst: &dyn AsRawFd,
+    dst_offset: u64,
+    len: usize,
+    cross_fs: bool,
+) -> io::Result<usize> {
+    let src_offset = checked_off_t(src_offset, len)?;
+    let dst_offset = checked_off_t(dst_offset, len)?;
+    let mut copied = 0;
+    while copied < len {
+        let mut src_off = src_offset + copied as off_t;
+        let mut dst_off = dst_offset + copied as off_t;
+        // This is safe because the kernel only writes to the two offsets, which are valid, and
+        // doesn't access our memory otherwise.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                src.as_raw_fd(),
+                &mut src_off as *mut off_t,
+                dst.as_raw_fd(),
+                &mut dst_off as *mut off_t,
+                len - copied,
+                0u32,
+            )
+        };
+        if ret == 0 {
+            break;
+        }
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // Old kernels don't have the system call, and newer ones only support some pairs
+                // of file systems.
+                Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EOPNOTSUPP) => break,
+                Some(libc::EINVAL) if cross_fs => break,
+                _ => return Err(e),
+            }
+        }
+        copied += ret as usize;
+    }
+    Ok(copied)
+}
+
+// Calls `splice` once, retrying on `EINTR`.
+fn splice_once(
+    src: &dyn AsRawFd,
+    src_offset: Option<&mut off_t>,
+    dst: &dyn AsRawFd,
+    dst_offset: Option<&mut off_t>,
+    len: usize,
+) -> io::Result<usize> {
+    let src_off = src_offset.map_or(ptr::null_mut(), |off| off as *mut off_t);
+    let dst_off = dst_offset.map_or(ptr::null_mut(), |off| off as *mut off_t);
+    loop {
+        // This is safe because the kernel only writes to the offsets, which are either null or
+        // valid, and doesn't access our memory otherwise.
+        let ret = unsafe {
+            libc::splice(
+                src.as_raw_fd(),
+                src_off,
+                dst.as_raw_fd(),
+                dst_off,
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+// Returns true if `e` means that `splice` isn't supported for the files it was given.
+fn splice_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS))
+}
+
+// Copies up to `len` bytes between a pipe or a socket and a regular file with `splice`, as for
+// `kernel_copy`. The offset of the regular file is given, and `direct` is true if the other side
+// is a pipe. Otherwise it's a socket, and the data goes through an intermediate pipe, since one
+// side of a `splice` has to be a pipe.
+fn splice_all(
+    src: &dyn AsRawFd,
+    src_offset: Option<u64>,
+    dst: &dyn AsRawFd,
+    dst_offset: Option<u64>,
+    len: usize,
+    direct: bool,
+) -> io::Result<usize> {
+    let mut src_off = src_offset.map(|off| checked_off_t(off, len)).transpose()?;
+    let mut dst_off = dst_offset.map(|off| checked_off_t(off, len)).transpose()?;
+
+    if direct {
+        let mut copied = 0;
+        while copied < len {
+            match splice_once(src, src_off.as_mut(), dst, dst_off.as_mut(), len - copied) {
+                Ok(0) => break,
+                Ok(count) => copied += count,
+                Err(e) if splice_unsupported(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(copied);
+    }
+
+    let mut fds = [0 as c_int; 2];
+    // Safe because `fds` has room for the two fds, and we check the result.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the fds were just created, and nothing else owns them.
+    let (pipe_read, pipe_write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // Moving more than the capacity of the pipe into it at once would block until it's drained.
+    // Safe because F_GETPIPE_SZ doesn't access our memory.
+    let capacity = match unsafe { libc::fcntl(fds[1], libc::F_GETPIPE_SZ) } {
+        size if size > 0 => size as usize,
+        _ => pagesize(),
+    };
+
+    let mut copied = 0;
+    while copied < len {
+        let chunk = min(len - copied, capacity);
+        let filled = match splice_once(src, src_off.as_mut(), &pipe_write, None, chunk) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if copied == 0 && splice_unsupported(&e) => break,
+            Err(e) => return Err(e),
+        };
+        // The data in the pipe has already been consumed from `src`, so it can't be left behind
+        // for the fallback to pick up.
+        let mut drained = 0;
+        while drained < filled {
+            match splice_once(&pipe_read, None, dst, dst_off.as_mut(), filled - drained)? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                count => drained += count,
+            }
+        }
+        copied += filled;
+    }
+    Ok(copied)
+}
+
+/// The memory that backs a region of a `GuestMemory`.
+#[derive(Clone)]
+pub enum RegionBacking {
+    /// A range of the memfd that is shared by all the regions of the `GuestMemory` with this
+    /// backing, and that other processes can map using `offset_from_base`.
+    SharedMemfd,
+    /// Private anonymous memory, which can't be shared with other processes.
+    Anonymous,
+    /// The given file, starting at the given offset. The mapping is shared, so writes to the
+    /// region are carried through to the file.
+    File(Arc<File>, u64),
+}
+
+/// The configuration of a single region of a `GuestMemory` built with `GuestMemoryBuilder`.
+#[derive(Clone)]
+pub struct MemoryRegionOptions {
+    guest_base: GuestAddress,
+    size: u64,
+    backing: RegionBacking,
+    prot: Protection,
+    populate: bool,
+    dontdump: bool,
+    name: Option<String>,
+    contents: Option<Vec<u8>>,
+}
+
+impl MemoryRegionOptions {
+    /// Returns the options for a region of `size` bytes at `guest_base`, with the same defaults as
+    /// `GuestMemory::new`: read/write, backed by the shared memfd, populated lazily, and excluded
+    /// from core dumps.
+    pub fn new(guest_base: GuestAddress, size: u64) -> MemoryRegionOptions {
+        MemoryRegionOptions {
+            guest_base,
+            size,
+            backing: RegionBacking::SharedMemfd,
+            prot: Protection::read_write(),
+            populate: false,
+            dontdump: true,
+            name: None,
+            contents: None,
+        }
+    }
+
+    /// Returns the options for a read-only region of `size` bytes at `guest_base` that is
+    /// initialized with `image`, such as a firmware image that the guest must not overwrite.
+    pub fn rom<T: Into<Vec<u8>>>(
+        guest_base: GuestAddress,
+        size: u64,
+        image: T,
+    ) -> MemoryRegionOptions {
+        MemoryRegionOptions::new(guest_base, size)
+            .protection(Protection::read())
+            .contents(image)
+    }
+
+    /// Sets the memory that backs the region.
+    pub fn backing(mut self, backing: RegionBacking) -> MemoryRegionOptions {
+        self.backing = backing;
+        self
+    }
+
+    /// Sets the protection the region is mapped with.
+    pub fn protection(mut self, prot: Protection) -> MemoryRegionOptions {
+        self.prot = prot;
+        self
+    }
+
+    /// Sets whether all the pages of the region are faulted in when it is mapped, like
+    /// `MemoryMapping::from_fd_offset_populate` does.
+    pub fn populate(mut self, populate: bool) -> MemoryRegionOptions {
+        self.populate = populate;
+        self
+    }
+
+    /// Sets whether the region is excluded from core dumps with `MADV_DONTDUMP`.
+    pub fn dontdump(mut self, dontdump: bool) -> MemoryRegionOptions {
+        self.dontdump = dontdump;
+        self
+    }
+
+    /// Sets a human-readable name for the region, as returned by `GuestMemory::region_name`.
+    pub fn name<T: Into<String>>(mut self, name: T) -> MemoryRegionOptions {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the data that the start of the region is initialized with when it is built. The data
+    /// is written before the protection is applied, so this also works for read-only regions.
+    pub fn contents<T: Into<Vec<u8>>>(mut self, contents: T) -> MemoryRegionOptions {
+        self.contents = Some(contents.into());
+        self
+    }
+}
+
+/// Builds a `GuestMemory` whose regions can each be configured with `MemoryRegionOptions`.
+#[derive(Clone, Default)]
+pub struct GuestMemoryBuilder {
+    regions: Vec<MemoryRegionOptions>,
+    track_dirty: bool,
+    huge_page_size: Option<HugePageSize>,
+    guard_pages: bool,
+    hotplug: bool,
+}
+
+impl GuestMemoryBuilder {
+    /// Returns a builder without any regions.
+    pub fn new() -> GuestMemoryBuilder {
+        GuestMemoryBuilder::default()
+    }
+
+    /// Adds a region. Regions must be added in order of their guest addresses, and must not
+    /// overlap.
+    pub fn region(mut self, options: MemoryRegionOptions) -> GuestMemoryBuilder {
+        self.regions.push(options);
+        self
+    }
+
+    /// Sets whether the pages written to in each region are tracked, as for
+    /// `GuestMemory::new_with_dirty_tracking`.
+    pub fn dirty_tracking(mut self, track_dirty: bool) -> GuestMemoryBuilder {
+        self.track_dirty = track_dirty;
+        self
+    }
+
+    /// Backs the shared memfd with huge pages of `page_size`, as for `GuestMemory::new_hugetlb`.
+    /// The regions with other backings are not affected.
+    pub fn huge_page_size(mut self, page_size: HugePageSize) -> GuestMemoryBuilder {
+        self.huge_page_size = Some(page_size);
+        self
+    }
+
+    /// Sets whether each region is surrounded by inaccessible guard pages, so that accesses that
+    /// run past its ends through host pointers fault instead of touching other mappings. The guard
+    /// areas are one huge page in size if the memfd is backed by huge pages, and also apply to
+    /// regions that are hotplugged later.
+    pub fn guard_pages(mut self, guard_pages: bool) -> GuestMemoryBuilder {
+        self.guard_pages = guard_pages;
+        self
+    }
+
+    /// Sets whether regions can be added with `GuestMemory::insert_region` later. Otherwise the
+    /// memfd is sealed against growing, so the processes it's shared with can rely on its size.
+    pub fn hotplug(mut self, hotplug: bool) -> GuestMemoryBuilder {
+        self.hotplug = hotplug;
+        self
+    }
+
+    /// Maps all the regions and returns the resulting `GuestMemory`.
+    pub fn build(self) -> Result<GuestMemory> {
+        let mut memfd_size = 0u64;
+        for options in &self.regions {
+            let alignment = match options.backing {
+                RegionBacking::SharedMemfd => GuestMemory::alignment(self.huge_page_size),
+                _ => pagesize() as u64,
+            };
+            if options.size % alignment != 0 {
+                return Err(Error::MemoryNotAligned);
+            }
+            // Every region has to end within the guest address space, which `MemoryRegion::end`
+            // relies on.
+            options
+                .guest_base
+                .checked_add(options.size)
+                .ok_or(Error::MemoryRegionTooLarge(options.size))?;
+            if let RegionBacking::SharedMemfd = options.backing {
+                memfd_size = memfd_size
+                    .checked_add(options.size)
+                    .ok_or(Error::MemoryRegionTooLarge(options.size))?;
+            }
+        }
+
+        let memfd = Arc::new(GuestMemory::create_memfd(
+            memfd_size,
+            self.huge_page_size,
+            self.hotplug,
+        )?);
+        let memfd_space = Arc::new(Mutex::new(MemfdSpace::new(memfd_size)));
+        let guard_size = if self.guard_pages {
+            GuestMemory::alignment(self.huge_page_size) as usize
+        } else {
+            0
+        };
+        let mut regions = Vec::<MemoryRegion>::new();
+        let mut offset = 0;
+
+        for options in self.regions {
+            if let Some(last) = regions.last() {
+                if last
+                    .guest_base
+                    .checked_add(last.mapping.size() as u64)
+                    .map_or(true, |a| a > options.guest_base)
+                {
+                    return Err(Error::MemoryRegionOverlap);
+                }
+            }
+
+            let size = usize::try_from(options.size)
+                .map_err(|_| Error::MemoryRegionTooLarge(options.size))?;
+            if let Some(contents) = &options.contents {
+                if contents.len() > size {
+                    return Err(Error::RegionContentsTooLarge(contents.len()));
+                }
+            }
+            // The region is mapped writable until its contents are filled in.
+            let map_prot = if options.contents.is_some() {
+                options.prot.set_write()
+            } else {
+                options.prot
+            };
+            let populate = if options.populate {
+                libc::MAP_POPULATE
+            } else {
+                0
+            };
+            let (fd, flags, memfd_offset, memfd_range): (Option<(&dyn AsRawFd, u64)>, _, _, _) =
+                match &options.backing {
+                    RegionBacking::SharedMemfd => {
+                        let memfd_offset = offset;
+                        offset += size as u64;
+                        (
+                            Some((&*memfd, memfd_offset)),
+                            libc::MAP_SHARED,
+                            memfd_offset,
+                            MemfdRange::new(&memfd, &memfd_space, memfd_offset, size as u64),
+                        )
+                    }
+                    RegionBacking::Anonymous => (
+                        None,
+                        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                        0,
+                        None,
+                    ),
+                    RegionBacking::File(file, file_offset) => {
+                        check_file_range(file, *file_offset, options.size)?;
+                        (
+                            Some((file.as_ref(), *file_offset)),
+                            libc::MAP_SHARED,
+                            0,
+                            None,
+                        )
+                    }
+                };
+            let align = match options.backing {
+                RegionBacking::SharedMemfd => GuestMemory::alignment(self.huge_page_size),
+                _ => pagesize() as u64,
+            };
+            let mapping = MemoryMapping::new_guarded_flags(
+                size,
+                guard_size,
+                align as usize,
+                flags | populate,
+                fd,
+                map_prot,
+            );
+            let mut mapping = mapping.map_err(Error::MemoryMappingFailed)?;
+            if let Some(contents) = &options.contents {
+                mapping
+                    .write_slice(contents, 0)
+                    .map_err(|e| Error::MemoryAccess(options.guest_base, e))?;
+                if map_prot != options.prot {
+                    let size = mapping.size();
+                    // Safe because the region only gets written through after checking that it
+                    // is writable.
+                    unsafe { mapping.set_protection(0, size, options.prot) }
+                        .map_err(Error::MemoryMappingFailed)?;
+                }
+            }
+            if !options.dontdump {
+                mapping
+                    .advise(0, mapping.size(), Advice::DoDump)
+                    .map_err(Error::MemoryMappingFailed)?;
+            }
+
+            regions.push(MemoryRegion {
+                mapping: Arc::new(mapping),
+                guest_base: options.guest_base,
+                memfd_offset,
+                _memfd_range: memfd_range,
+                backing: options.backing,
+                prot: options.prot,
+                name: options.name,
+                dirty: GuestMemory::create_bitmap(size, self.track_dirty),
+            });
+        }
+
+        Ok(GuestMemory {
+            regions: Arc::new(regions),
+            mmio: Arc::new(Vec::new()),
+            memfd,
+            memfd_space,
+            track_dirty: self.track_dirty,
+            huge_page_size: self.huge_page_size,
+            guard_size,
+            last_region: AtomicUsize::new(0),
+        })
     }
 }
 
@@ -660,110 +2436,610 @@ impl GuestMemory {
 mod tests {
     use super::super::shm::kernel_has_memfd;
     use super::*;
+    use std::io::{Seek, SeekFrom};
+
+    #[test]
+    fn test_alignment() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+
+        assert!(GuestMemory::new(&vec![(start_addr1, 0x100), (start_addr2, 0x400)]).is_err());
+        assert!(GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).is_ok());
+    }
+
+    #[test]
+    fn two_regions() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x4000);
+        assert!(GuestMemory::new(&vec![(start_addr1, 0x4000), (start_addr2, 0x4000)]).is_ok());
+    }
+
+    #[test]
+    fn overlap_memory() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        assert!(GuestMemory::new(&vec![(start_addr1, 0x2000), (start_addr2, 0x2000)]).is_err());
+    }
+
+    #[test]
+    fn region_past_end_of_address_space() {
+        let ps = pagesize() as u64;
+        match GuestMemory::new(&[
+            (GuestAddress(0x0), ps),
+            (GuestAddress(0u64.wrapping_sub(ps)), 2 * ps),
+        ]) {
+            Err(Error::MemoryRegionTooLarge(size)) => assert_eq!(size, 2 * ps),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert!(GuestMemory::new(&[(GuestAddress(0u64.wrapping_sub(2 * ps)), ps)]).is_ok());
+    }
+
+    #[test]
+    fn region_hole() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x4000);
+        let gm = GuestMemory::new(&vec![(start_addr1, 0x2000), (start_addr2, 0x2000)]).unwrap();
+        assert_eq!(gm.address_in_range(GuestAddress(0x1000)), true);
+        assert_eq!(gm.address_in_range(GuestAddress(0x3000)), false);
+        assert_eq!(gm.address_in_range(GuestAddress(0x5000)), true);
+        assert_eq!(gm.address_in_range(GuestAddress(0x6000)), false);
+        assert_eq!(gm.address_in_range(GuestAddress(0x6000)), false);
+        assert_eq!(
+            gm.range_overlap(GuestAddress(0x1000), GuestAddress(0x3000)),
+            true
+        );
+        assert_eq!(
+            gm.range_overlap(GuestAddress(0x3000), GuestAddress(0x4000)),
+            false
+        );
+        assert_eq!(
+            gm.range_overlap(GuestAddress(0x3000), GuestAddress(0x7000)),
+            true
+        );
+        assert!(gm.checked_offset(GuestAddress(0x1000), 0x1000).is_none());
+        assert!(gm.checked_offset(GuestAddress(0x5000), 0x800).is_some());
+        assert!(gm.checked_offset(GuestAddress(0x5000), 0x1000).is_none());
+    }
+
+    #[test]
+    fn test_read_u64() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+
+        let val1: u64 = 0xaa55aa55aa55aa55;
+        let val2: u64 = 0x55aa55aa55aa55aa;
+        gm.write_obj_at_addr(val1, GuestAddress(0x500)).unwrap();
+        gm.write_obj_at_addr(val2, GuestAddress(0x1000 + 32))
+            .unwrap();
+        let num1: u64 = gm.read_obj_from_addr(GuestAddress(0x500)).unwrap();
+        let num2: u64 = gm.read_obj_from_addr(GuestAddress(0x1000 + 32)).unwrap();
+        assert_eq!(val1, num1);
+        assert_eq!(val2, num2);
+    }
+
+    #[test]
+    fn test_ref_load_u64() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+
+        let val1: u64 = 0xaa55aa55aa55aa55;
+        let val2: u64 = 0x55aa55aa55aa55aa;
+        gm.write_obj_at_addr(val1, GuestAddress(0x500)).unwrap();
+        gm.write_obj_at_addr(val2, GuestAddress(0x1000 + 32))
+            .unwrap();
+        let num1: u64 = gm.get_ref_at_addr(GuestAddress(0x500)).unwrap().load();
+        let num2: u64 = gm
+            .get_ref_at_addr(GuestAddress(0x1000 + 32))
+            .unwrap()
+            .load();
+        assert_eq!(val1, num1);
+        assert_eq!(val2, num2);
+    }
+
+    #[test]
+    fn test_ref_store_u64() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+
+        let val1: u64 = 0xaa55aa55aa55aa55;
+        let val2: u64 = 0x55aa55aa55aa55aa;
+        gm.get_ref_at_addr(GuestAddress(0x500)).unwrap().store(val1);
+        gm.get_ref_at_addr(GuestAddress(0x1000 + 32))
+            .unwrap()
+            .store(val2);
+        let num1: u64 = gm.read_obj_from_addr(GuestAddress(0x500)).unwrap();
+        let num2: u64 = gm.read_obj_from_addr(GuestAddress(0x1000 + 32)).unwrap();
+        assert_eq!(val1, num1);
+        assert_eq!(val2, num2);
+    }
+
+    #[test]
+    fn cross_region_access() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+
+        let buf = [0xa5u8; 0x20];
+        assert_eq!(gm.write_at_addr(&buf, GuestAddress(0xff0)).unwrap(), 0x20);
+        let mut read_buf = [0u8; 0x20];
+        gm.read_exact_at_addr(&mut read_buf, GuestAddress(0xff0))
+            .unwrap();
+        assert_eq!(buf, read_buf);
+
+        let val: u64 = 0x0123456789abcdef;
+        gm.write_obj_at_addr(val, GuestAddress(0xffc)).unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0xffc)).unwrap(),
+            val
+        );
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(0xffc)).unwrap(),
+            0x89abcdef
+        );
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(0x1000)).unwrap(),
+            0x01234567
+        );
+    }
+
+    #[test]
+    fn cross_region_file_access_at() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x1000), 0x1000)])
+            .unwrap();
+        let mut file = SharedMemory::anon().unwrap();
+        file.set_size(0x100).unwrap();
+
+        gm.write_obj_at_addr(0x0123_4567_89ab_cdefu64, GuestAddress(0xffc))
+            .unwrap();
+        gm.write_from_memory_at(GuestAddress(0xffc), &file, 0x10, 8)
+            .unwrap();
+        gm.read_to_memory_at(GuestAddress(0x1ff8), &file, 0x10, 8)
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x1ff8)).unwrap(),
+            0x0123_4567_89ab_cdef
+        );
+        match gm.read_to_memory_at(GuestAddress(0x1ffc), &file, 0, 8) {
+            Err(Error::InvalidGuestAddress(a)) => assert_eq!(a, GuestAddress(0x2000)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Failures in a later chunk report the address of that chunk.
+        match gm.read_to_memory_at(GuestAddress(0xff8), &file, 0xf8, 0x10) {
+            Err(Error::MemoryAccess(a, _)) => assert_eq!(a, GuestAddress(0x1000)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn vectored_file_io() {
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), 0x1000))
+            .region(MemoryRegionOptions::new(GuestAddress(0x1000), 0x1000))
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(0x4000),
+                0x1000,
+                &[0u8][..],
+            ))
+            .build()
+            .unwrap();
+        let mut file = SharedMemory::anon().unwrap();
+        let contents: Vec<u8> = (0..0x40).collect();
+        std::io::Write::write_all(&mut file, &contents).unwrap();
+
+        // The first descriptor spans both regions.
+        let descs = [(GuestAddress(0xff8), 0x10), (GuestAddress(0x100), 0x8)];
+        assert_eq!(gm.read_vectored_from_fd(&file, 0x8, &descs).unwrap(), 0x18);
+        let mut buf = [0u8; 0x10];
+        gm.read_exact_at_addr(&mut buf, GuestAddress(0xff8))
+            .unwrap();
+        assert_eq!(&buf[..], &contents[0x8..0x18]);
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x100)).unwrap(),
+            u64::from_ne_bytes([0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f])
+        );
+        // Reads stop at the end of the file.
+        assert_eq!(gm.read_vectored_from_fd(&file, 0x30, &descs).unwrap(), 0x10);
+
+        assert_eq!(gm.write_vectored_to_fd(&file, 0x40, &descs).unwrap(), 0x18);
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 0x58);
+        let mut buf = [0u8; 0x18];
+        file.seek(SeekFrom::Start(0x40)).unwrap();
+        std::io::Read::read_exact(&mut file, &mut buf).unwrap();
+        assert_eq!(&buf[..0x10], &contents[0x30..0x40]);
+        assert_eq!(&buf[0x10..], &contents[0x18..0x20]);
+
+        // More descriptors than fit in a single system call.
+        let descs: Vec<_> = (0..0x800).map(|i| (GuestAddress(i), 1)).collect();
+        assert_eq!(gm.write_vectored_to_fd(&file, 0, &descs).unwrap(), 0x800);
+        assert_eq!(gm.read_vectored_from_fd(&file, 0, &descs).unwrap(), 0x800);
+
+        match gm.read_vectored_from_fd(&file, 0, &[(GuestAddress(0x1ff8), 0x10)]) {
+            Err(Error::InvalidGuestAddress(a)) => assert_eq!(a, GuestAddress(0x2000)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.read_vectored_from_fd(&file, 0, &[(GuestAddress(0x4000), 0x10)]) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(0x4000)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            gm.write_vectored_to_fd(&file, 0, &[(GuestAddress(0x4000), 0x10)])
+                .unwrap(),
+            0x10
+        );
+    }
+
+    #[test]
+    fn copy_file() {
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), 0x1000))
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x1000), 0x1000)
+                    .backing(RegionBacking::Anonymous),
+            )
+            .dirty_tracking(true)
+            .build()
+            .unwrap();
+        let mut file = SharedMemory::anon().unwrap();
+        let contents: Vec<u8> = (0..0x40).collect();
+        std::io::Write::write_all(&mut file, &contents).unwrap();
+
+        // The first half is copied within the kernel, and the second half, which is in the
+        // anonymous region, through memory.
+        gm.copy_from_file(GuestAddress(0xfe0), &file, 0x10, 0x30)
+            .unwrap();
+        let mut buf = [0u8; 0x30];
+        gm.read_exact_at_addr(&mut buf, GuestAddress(0xfe0))
+            .unwrap();
+        assert_eq!(&buf[..], &contents[0x10..]);
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![1]));
+        assert_eq!(gm.get_and_clear_dirty_bitmap(1), Some(vec![1]));
+
+        gm.copy_to_file(GuestAddress(0xfe0), &file, 0x40, 0x30)
+            .unwrap();
+        let mut buf = [0u8; 0x30];
+        file.seek(SeekFrom::Start(0x40)).unwrap();
+        std::io::Read::read_exact(&mut file, &mut buf).unwrap();
+        assert_eq!(&buf[..], &contents[0x10..]);
+
+        // Files that can't be copied from in the kernel fall back to reading.
+        let zero = File::open("/dev/zero").unwrap();
+        gm.copy_from_file(GuestAddress(0xff0), &zero, 0, 0x20)
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0xffc)).unwrap(),
+            0
+        );
+
+        match gm.copy_from_file(GuestAddress(0x0), &file, 0x60, 0x40) {
+            Err(Error::MemoryAccess(_, mmap::Error::ReadToMemory(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Offsets that don't fit in an off_t are rejected instead of wrapping around.
+        match gm.copy_from_file(GuestAddress(0x0), &file, off_t::MAX as u64, 0x10) {
+            Err(Error::MemoryAccess(_, mmap::Error::ReadToMemory(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput)
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn copy_stream() {
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), 0x1000))
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x1000), 0x1000)
+                    .backing(RegionBacking::Anonymous),
+            )
+            .build()
+            .unwrap();
+        let contents: Vec<u8> = (0..0x40).collect();
+
+        // Pipes are spliced from directly, and the file offset is ignored.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (pipe_read, mut pipe_write) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        std::io::Write::write_all(&mut pipe_write, &contents).unwrap();
+        gm.copy_from_file(GuestAddress(0xfe0), &pipe_read, 0x1234, 0x40)
+            .unwrap();
+        let mut buf = [0u8; 0x40];
+        gm.read_exact_at_addr(&mut buf, GuestAddress(0xfe0))
+            .unwrap();
+        assert_eq!(&buf[..], &contents[..]);
+
+        gm.copy_to_file(GuestAddress(0xfe0), &pipe_write, 0x1234, 0x40)
+            .unwrap();
+        let mut buf = [0u8; 0x40];
+        std::io::Read::read_exact(&mut &pipe_read, &mut buf).unwrap();
+        assert_eq!(&buf[..], &contents[..]);
+
+        // Sockets go through an intermediate pipe.
+        let (mut sender, receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut sender, &contents[..0x20]).unwrap();
+        gm.copy_from_file(GuestAddress(0x10), &receiver, 0, 0x20)
+            .unwrap();
+        gm.copy_to_file(GuestAddress(0x10), &receiver, 0, 0x20)
+            .unwrap();
+        let mut buf = [0u8; 0x20];
+        std::io::Read::read_exact(&mut sender, &mut buf).unwrap();
+        assert_eq!(&buf[..], &contents[..0x20]);
+
+        drop(sender);
+        match gm.copy_from_file(GuestAddress(0x0), &receiver, 0, 0x10) {
+            Err(Error::MemoryAccess(_, mmap::Error::ReadToMemory(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
 
     #[test]
-    fn test_alignment() {
+    fn cross_region_access_hole() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
+        let start_addr3 = GuestAddress(0x4000);
+        let gm = GuestMemory::new(&[
+            (start_addr1, 0x1000),
+            (start_addr2, 0x1000),
+            (start_addr3, 0x1000),
+        ])
+        .unwrap();
 
-        assert!(GuestMemory::new(&vec![(start_addr1, 0x100), (start_addr2, 0x400)]).is_err());
-        assert!(GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).is_ok());
-    }
+        // Accesses that run into the hole are truncated.
+        let buf = [0x5au8; 0x20];
+        assert_eq!(gm.write_at_addr(&buf, GuestAddress(0x1ff0)).unwrap(), 0x10);
+        let mut read_buf = [0u8; 0x20];
+        assert_eq!(
+            gm.read_at_addr(&mut read_buf, GuestAddress(0x1fe0))
+                .unwrap(),
+            0x20
+        );
+        assert_eq!(read_buf[0x10..], buf[..0x10]);
+        match gm.write_all_at_addr(&buf, GuestAddress(0x1ff0)) {
+            Err(Error::ShortWrite {
+                expected: 0x20,
+                completed: 0x10,
+            }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
 
-    #[test]
-    fn two_regions() {
-        let start_addr1 = GuestAddress(0x0);
-        let start_addr2 = GuestAddress(0x4000);
-        assert!(GuestMemory::new(&vec![(start_addr1, 0x4000), (start_addr2, 0x4000)]).is_ok());
+        // Accesses that must complete report the address of the hole.
+        match gm.read_obj_from_addr::<u64>(GuestAddress(0x1ffc)) {
+            Err(Error::InvalidGuestAddress(GuestAddress(0x2000))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.write_obj_at_addr(0u64, GuestAddress(0x4ffc)) {
+            Err(Error::InvalidGuestAddress(GuestAddress(0x5000))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.write_at_addr(&buf, GuestAddress(0x3000)) {
+            Err(Error::InvalidGuestAddress(GuestAddress(0x3000))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
     }
 
     #[test]
-    fn overlap_memory() {
-        let start_addr1 = GuestAddress(0x0);
-        let start_addr2 = GuestAddress(0x1000);
-        assert!(GuestMemory::new(&vec![(start_addr1, 0x2000), (start_addr2, 0x2000)]).is_err());
+    fn region_lookup() {
+        let ranges: Vec<(GuestAddress, u64)> = (0..64)
+            .map(|i| (GuestAddress(i * 0x3000), 0x1000 * (i % 2 + 1)))
+            .collect();
+        let gm = GuestMemory::new(&ranges).unwrap();
+
+        // Go back and forth between regions so that both cache hits and misses are exercised.
+        for &i in &[0u64, 63, 63, 1, 32, 0, 0, 31] {
+            let start = GuestAddress(i * 0x3000);
+            let size = 0x1000 * (i % 2 + 1);
+            assert!(gm.address_in_range(start));
+            assert!(gm.address_in_range(start.unchecked_add(size - 1)));
+            assert!(!gm.address_in_range(start.unchecked_add(size)));
+            gm.write_obj_at_addr(i, start).unwrap();
+            assert_eq!(gm.read_obj_from_addr::<u64>(start).unwrap(), i);
+        }
+        assert!(!gm.address_in_range(GuestAddress(64 * 0x3000)));
+        assert_eq!(gm.end_addr(), GuestAddress(63 * 0x3000 + 0x2000));
     }
 
     #[test]
-    fn region_hole() {
-        let start_addr1 = GuestAddress(0x0);
-        let start_addr2 = GuestAddress(0x4000);
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x2000), (start_addr2, 0x2000)]).unwrap();
-        assert_eq!(gm.address_in_range(GuestAddress(0x1000)), true);
-        assert_eq!(gm.address_in_range(GuestAddress(0x3000)), false);
-        assert_eq!(gm.address_in_range(GuestAddress(0x5000)), true);
-        assert_eq!(gm.address_in_range(GuestAddress(0x6000)), false);
-        assert_eq!(gm.address_in_range(GuestAddress(0x6000)), false);
+    fn hotplug() {
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), 0x1000))
+            .region(MemoryRegionOptions::new(GuestAddress(0x4000), 0x1000))
+            .hotplug(true)
+            .build()
+            .unwrap();
+        gm.write_obj_at_addr(0x1122u16, GuestAddress(0x4000))
+            .unwrap();
+
+        let plugged = gm.insert_region(GuestAddress(0x1000), 0x2000).unwrap();
+        assert_eq!(plugged.num_regions(), 3);
+        assert_eq!(plugged.memory_size(), 0x4000);
         assert_eq!(
-            gm.range_overlap(GuestAddress(0x1000), GuestAddress(0x3000)),
-            true
+            plugged.offset_from_base(GuestAddress(0x1000)).unwrap(),
+            0x2000
         );
+        assert!(!gm.address_in_range(GuestAddress(0x1000)));
+
+        // Existing regions are shared with the original instance.
         assert_eq!(
-            gm.range_overlap(GuestAddress(0x3000), GuestAddress(0x4000)),
-            false
+            plugged.get_host_address(GuestAddress(0x4000)).unwrap(),
+            gm.get_host_address(GuestAddress(0x4000)).unwrap()
         );
         assert_eq!(
-            gm.range_overlap(GuestAddress(0x3000), GuestAddress(0x7000)),
-            true
+            plugged
+                .read_obj_from_addr::<u16>(GuestAddress(0x4000))
+                .unwrap(),
+            0x1122
         );
-        assert!(gm.checked_offset(GuestAddress(0x1000), 0x1000).is_none());
-        assert!(gm.checked_offset(GuestAddress(0x5000), 0x800).is_some());
-        assert!(gm.checked_offset(GuestAddress(0x5000), 0x1000).is_none());
-    }
 
-    #[test]
-    fn test_read_u64() {
-        let start_addr1 = GuestAddress(0x0);
-        let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        // The new region is adjacent to the first one.
+        plugged
+            .write_obj_at_addr(0x3344u32, GuestAddress(0xffe))
+            .unwrap();
+        assert_eq!(
+            plugged
+                .read_obj_from_addr::<u32>(GuestAddress(0xffe))
+                .unwrap(),
+            0x3344
+        );
 
-        let val1: u64 = 0xaa55aa55aa55aa55;
-        let val2: u64 = 0x55aa55aa55aa55aa;
-        gm.write_obj_at_addr(val1, GuestAddress(0x500)).unwrap();
-        gm.write_obj_at_addr(val2, GuestAddress(0x1000 + 32))
+        let unplugged = plugged.remove_region(GuestAddress(0x1000)).unwrap();
+        assert_eq!(unplugged.num_regions(), 2);
+        assert!(!unplugged.address_in_range(GuestAddress(0x1000)));
+        assert!(plugged.address_in_range(GuestAddress(0x1000)));
+
+        // Plugging again doesn't reuse the memfd range of the removed region while `plugged` still
+        // maps it.
+        let mut replugged = unplugged
+            .insert_region(GuestAddress(0x2000), 0x1000)
             .unwrap();
-        let num1: u64 = gm.read_obj_from_addr(GuestAddress(0x500)).unwrap();
-        let num2: u64 = gm.read_obj_from_addr(GuestAddress(0x1000 + 32)).unwrap();
-        assert_eq!(val1, num1);
-        assert_eq!(val2, num2);
+        assert_eq!(
+            replugged.offset_from_base(GuestAddress(0x2000)).unwrap(),
+            0x4000
+        );
+
+        // Once it's gone, the range is reused, and its old contents are released.
+        plugged
+            .write_obj_at_addr(0x5566u16, GuestAddress(0x1000))
+            .unwrap();
+        drop(plugged);
+        replugged = replugged
+            .insert_region(GuestAddress(0x1000), 0x1000)
+            .unwrap();
+        assert_eq!(
+            replugged.offset_from_base(GuestAddress(0x1000)).unwrap(),
+            0x2000
+        );
+        assert_eq!(
+            replugged
+                .read_obj_from_addr::<u16>(GuestAddress(0x1000))
+                .unwrap(),
+            0
+        );
+        replugged = replugged
+            .insert_region(GuestAddress(0x3000), 0x1000)
+            .unwrap();
+        assert_eq!(
+            replugged.offset_from_base(GuestAddress(0x3000)).unwrap(),
+            0x3000
+        );
+        replugged = replugged
+            .insert_region(GuestAddress(0x5000), 0x1000)
+            .unwrap();
+        assert_eq!(
+            replugged.offset_from_base(GuestAddress(0x5000)).unwrap(),
+            0x5000
+        );
+
+        // Adjacent released ranges are merged, so they can hold a larger region.
+        replugged = replugged
+            .remove_region(GuestAddress(0x1000))
+            .unwrap()
+            .remove_region(GuestAddress(0x3000))
+            .unwrap();
+        replugged = replugged
+            .insert_region(GuestAddress(0x6000), 0x2000)
+            .unwrap();
+        assert_eq!(
+            replugged.offset_from_base(GuestAddress(0x6000)).unwrap(),
+            0x2000
+        );
     }
 
     #[test]
-    fn test_ref_load_u64() {
-        let start_addr1 = GuestAddress(0x0);
-        let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+    fn hotplug_errors() {
+        let ranges = [(GuestAddress(0x0), 0x2000), (GuestAddress(0x4000), 0x2000)];
+        // The memfd is sealed against growing unless hotplug is enabled.
+        if kernel_has_memfd() {
+            let sealed = GuestMemory::new(&ranges).unwrap();
+            match sealed.insert_region(GuestAddress(0x2000), 0x1000) {
+                Err(Error::HotplugNotEnabled) => {}
+                r => panic!("unexpected result: {:?}", r.map(|_| ())),
+            }
+        }
 
-        let val1: u64 = 0xaa55aa55aa55aa55;
-        let val2: u64 = 0x55aa55aa55aa55aa;
-        gm.write_obj_at_addr(val1, GuestAddress(0x500)).unwrap();
-        gm.write_obj_at_addr(val2, GuestAddress(0x1000 + 32))
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(ranges[0].0, ranges[0].1))
+            .region(MemoryRegionOptions::new(ranges[1].0, ranges[1].1))
+            .hotplug(true)
+            .build()
             .unwrap();
-        let num1: u64 = gm.get_ref_at_addr(GuestAddress(0x500)).unwrap().load();
-        let num2: u64 = gm
-            .get_ref_at_addr(GuestAddress(0x1000 + 32))
-            .unwrap()
-            .load();
-        assert_eq!(val1, num1);
-        assert_eq!(val2, num2);
+
+        for &(base, size) in &[(0x1000, 0x1000), (0x3000, 0x2000), (0x4000, 0x1000)] {
+            match gm.insert_region(GuestAddress(base), size) {
+                Err(Error::MemoryRegionOverlap) => {}
+                r => panic!("unexpected result: {:?}", r.map(|_| ())),
+            }
+        }
+        match gm.insert_region(GuestAddress(0x2000), 0x100) {
+            Err(Error::MemoryNotAligned) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        match gm.remove_region(GuestAddress(0x1000)) {
+            Err(Error::InvalidGuestAddress(GuestAddress(0x1000))) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        gm.insert_region(GuestAddress(0x2000), 0x2000).unwrap();
     }
 
     #[test]
-    fn test_ref_store_u64() {
-        let start_addr1 = GuestAddress(0x0);
-        let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+    fn dirty_tracking() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps * 4))
+            .region(MemoryRegionOptions::new(GuestAddress(ps * 4), ps * 4))
+            .dirty_tracking(true)
+            .hotplug(true)
+            .build()
+            .unwrap();
+        assert!(gm.dirty_tracking_enabled());
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0]));
 
-        let val1: u64 = 0xaa55aa55aa55aa55;
-        let val2: u64 = 0x55aa55aa55aa55aa;
-        gm.get_ref_at_addr(GuestAddress(0x500)).unwrap().store(val1);
-        gm.get_ref_at_addr(GuestAddress(0x1000 + 32))
+        // Reads don't dirty anything.
+        gm.read_obj_from_addr::<u64>(GuestAddress(0)).unwrap();
+        let mut buf = [0u8; 16];
+        gm.read_at_addr(&mut buf, GuestAddress(ps)).unwrap();
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0]));
+
+        // Slices are assumed to be written to, even if they're only read.
+        gm.get_ref_at_addr::<u32>(GuestAddress(ps)).unwrap().load();
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0b10]));
+
+        gm.write_obj_at_addr(1u64, GuestAddress(ps * 4 - 4))
+            .unwrap();
+        gm.write_at_addr(&buf, GuestAddress(ps * 2)).unwrap();
+        gm.get_ref_at_addr::<u32>(GuestAddress(ps * 6))
             .unwrap()
-            .store(val2);
-        let num1: u64 = gm.read_obj_from_addr(GuestAddress(0x500)).unwrap();
-        let num2: u64 = gm.read_obj_from_addr(GuestAddress(0x1000 + 32)).unwrap();
-        assert_eq!(val1, num1);
-        assert_eq!(val2, num2);
+            .store(2);
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0b1100]));
+        assert_eq!(gm.get_and_clear_dirty_bitmap(1), Some(vec![0b0101]));
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0]));
+        assert_eq!(gm.get_and_clear_dirty_bitmap(2), None);
+
+        let file = std::fs::File::open("/dev/zero").unwrap();
+        gm.read_to_memory(GuestAddress(ps * 3), &file, ps as usize * 2)
+            .unwrap();
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0b1000]));
+        assert_eq!(gm.get_and_clear_dirty_bitmap(1), Some(vec![0b0001]));
+
+        // Hotplugged regions are tracked too.
+        let plugged = gm.insert_region(GuestAddress(ps * 16), ps).unwrap();
+        plugged
+            .write_obj_at_addr(3u8, GuestAddress(ps * 16))
+            .unwrap();
+        assert_eq!(plugged.get_and_clear_dirty_bitmap(2), Some(vec![1]));
+
+        let untracked = GuestMemory::new(&[(GuestAddress(0x0), ps)]).unwrap();
+        assert!(!untracked.dirty_tracking_enabled());
+        untracked.write_obj_at_addr(1u64, GuestAddress(0)).unwrap();
+        assert_eq!(untracked.get_and_clear_dirty_bitmap(0), None);
     }
 
     #[test]
@@ -840,4 +3116,475 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn hugetlb() {
+        let huge = HugePageSize::Size2M.size();
+        match GuestMemory::new_hugetlb(&[(GuestAddress(0x0), 0x1000)], HugePageSize::Size2M) {
+            Err(Error::MemoryNotAligned) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let gm = match GuestMemory::new_hugetlb(
+            &[(GuestAddress(0x0), huge), (GuestAddress(huge * 4), huge)],
+            HugePageSize::Size2M,
+        ) {
+            Ok(gm) => gm,
+            // The kernel doesn't support hugetlb memfds, or no huge pages are reserved.
+            Err(Error::MemoryCreationFailed(_))
+            | Err(Error::MemorySetSizeFailed(_))
+            | Err(Error::MemoryMappingFailed(_)) => return,
+            Err(e) => panic!("failed to create hugetlb guest memory: {}", e),
+        };
+        assert_eq!(gm.huge_page_size(), Some(HugePageSize::Size2M));
+        gm.write_obj_at_addr(0x55aau16, GuestAddress(huge * 5 - 2))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u16>(GuestAddress(huge * 5 - 2))
+                .unwrap(),
+            0x55aa
+        );
+
+        match gm.insert_region(GuestAddress(huge * 2), 0x1000) {
+            Err(Error::MemoryNotAligned) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let ps = pagesize() as u64;
+        let path =
+            std::env::temp_dir().join(format!("guest_memory_builder_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(ps * 4).unwrap();
+        let file = Arc::new(file);
+
+        let gm = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), ps * 2)
+                    .populate(true)
+                    .dontdump(false)
+                    .name("ram"),
+            )
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 2), ps)
+                    .backing(RegionBacking::Anonymous)
+                    .name("scratch"),
+            )
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 4), ps)
+                    .backing(RegionBacking::File(file.clone(), ps * 2))
+                    .protection(Protection::read()),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(gm.num_regions(), 3);
+        assert_eq!(gm.memory_size(), ps * 4);
+        assert_eq!(gm.region_name(0), Some("ram"));
+        assert_eq!(gm.region_name(1), Some("scratch"));
+        assert_eq!(gm.region_name(2), None);
+        assert!(gm.region_protection(2) == Some(Protection::read()));
+
+        // Accesses spanning the memfd and anonymous regions are still split between them.
+        gm.write_obj_at_addr(0x1122_3344_5566_7788u64, GuestAddress(ps * 2 - 4))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(ps * 2 - 4))
+                .unwrap(),
+            0x1122_3344_5566_7788
+        );
+
+        // The file region shows the contents of the file at the given offset.
+        {
+            use std::os::unix::fs::FileExt;
+            file.write_at(&[0xa5], ps * 2 + 0x10).unwrap();
+        }
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(ps * 4 + 0x10))
+                .unwrap(),
+            0xa5
+        );
+
+        assert_eq!(gm.offset_from_base(GuestAddress(0x10)).unwrap(), 0x10);
+        for &addr in &[ps * 2, ps * 4] {
+            match gm.offset_from_base(GuestAddress(addr)) {
+                Err(Error::NotMemfdBacked(a)) => assert_eq!(a, GuestAddress(addr)),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        // Only the memfd region is reported with a memfd offset, while the file region's offset
+        // is in its own fd.
+        let mut offsets = Vec::new();
+        gm.with_regions::<_, ()>(|index, _, _, _, memfd_offset| {
+            offsets.push((index, memfd_offset));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(offsets, [(0, 0)]);
+        assert_eq!(
+            gm.region_fd(GuestAddress(ps * 4)).unwrap(),
+            file.as_raw_fd()
+        );
+        assert_eq!(
+            gm.offset_from_region_fd(GuestAddress(ps * 4 + 0x10))
+                .unwrap(),
+            ps * 2 + 0x10
+        );
+
+        let unaligned = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), 0x100)
+                    .backing(RegionBacking::Anonymous),
+            )
+            .build();
+        match unaligned {
+            Err(Error::MemoryNotAligned) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn region_fds() {
+        let ps = pagesize() as u64;
+        // A memfd created by another process, and an image file.
+        let mut memfd = SharedMemory::anon().unwrap();
+        memfd.set_size(ps * 4).unwrap();
+        let memfd: Arc<File> = Arc::new(memfd.into());
+        let path = std::env::temp_dir().join(format!("guest_memory_pmem_{}", std::process::id()));
+        let image = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        image.set_len(ps * 2).unwrap();
+        let image = Arc::new(image);
+
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps))
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 2), ps * 2)
+                    .backing(RegionBacking::File(memfd.clone(), ps * 2)),
+            )
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 8), ps)
+                    .backing(RegionBacking::Anonymous),
+            )
+            .build()
+            .unwrap();
+        let gm = gm
+            .insert_file_region(
+                GuestAddress(ps * 16),
+                ps * 2,
+                image.clone(),
+                0,
+                Protection::read_write(),
+            )
+            .unwrap();
+
+        // Each address is found in the fd of its own region.
+        assert_eq!(gm.region_fd(GuestAddress(0x10)).unwrap(), gm.as_raw_fd());
+        assert_eq!(gm.offset_from_region_fd(GuestAddress(0x10)).unwrap(), 0x10);
+        assert_eq!(
+            gm.region_fd(GuestAddress(ps * 3)).unwrap(),
+            memfd.as_raw_fd()
+        );
+        assert_eq!(
+            gm.offset_from_region_fd(GuestAddress(ps * 3 + 8)).unwrap(),
+            ps * 3 + 8
+        );
+        assert_eq!(
+            gm.region_fd(GuestAddress(ps * 17)).unwrap(),
+            image.as_raw_fd()
+        );
+        assert_eq!(gm.offset_from_region_fd(GuestAddress(ps * 17)).unwrap(), ps);
+        match gm.region_fd(GuestAddress(ps * 8)) {
+            Err(Error::AnonymousRegion(a)) => assert_eq!(a, GuestAddress(ps * 8)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.offset_from_region_fd(GuestAddress(ps * 10)) {
+            Err(Error::InvalidGuestAddress(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Writes to the hotplugged region are carried through to the image.
+        gm.write_obj_at_addr(0x5au8, GuestAddress(ps * 17 + 4))
+            .unwrap();
+        let mut buf = [0u8];
+        {
+            use std::os::unix::fs::FileExt;
+            image.read_exact_at(&mut buf, ps + 4).unwrap();
+        }
+        assert_eq!(buf[0], 0x5a);
+
+        // File regions must fit in their file and not overlap the other regions.
+        match gm.insert_file_region(
+            GuestAddress(ps * 32),
+            ps * 2,
+            image.clone(),
+            ps,
+            Protection::read(),
+        ) {
+            Err(Error::RegionOutsideFile { offset, size }) => {
+                assert_eq!((offset, size), (ps, ps * 2))
+            }
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        match gm.insert_file_region(
+            GuestAddress(ps * 17),
+            ps,
+            image.clone(),
+            0,
+            Protection::read(),
+        ) {
+            Err(Error::MemoryRegionOverlap) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        let too_large = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), ps * 4)
+                    .backing(RegionBacking::File(image, 0)),
+            )
+            .build();
+        match too_large {
+            Err(Error::RegionOutsideFile { .. }) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rom_region() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps))
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(ps),
+                ps,
+                &[0x11u8, 0x22, 0x33, 0x44][..],
+            ))
+            .build()
+            .unwrap();
+        assert!(gm.region_protection(1) == Some(Protection::read()));
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(ps)).unwrap(),
+            0x4433_2211
+        );
+
+        match gm.write_at_addr(&[0u8; 4], GuestAddress(ps)) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(ps)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.write_obj_at_addr(0u64, GuestAddress(ps - 4)) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(ps)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // The part of the write that lands in RAM still happens.
+        assert_eq!(
+            gm.write_at_addr(&[0xffu8; 8], GuestAddress(ps - 4))
+                .unwrap(),
+            4
+        );
+        let zero = File::open("/dev/zero").unwrap();
+        match gm.read_to_memory(GuestAddress(ps + 8), &zero, 4) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(ps + 8)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.get_slice_at_addr(GuestAddress(ps), 4) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(ps)),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        match gm.remove_range(GuestAddress(ps), ps) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(ps)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(ps)).unwrap(),
+            0x4433_2211
+        );
+
+        let too_large = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(0x0),
+                ps,
+                vec![0u8; ps as usize + 1],
+            ))
+            .build();
+        match too_large {
+            Err(Error::RegionContentsTooLarge(size)) => assert_eq!(size, ps as usize + 1),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn advise() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), ps * 2)
+                    .backing(RegionBacking::Anonymous),
+            )
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 2), ps * 2)
+                    .backing(RegionBacking::Anonymous),
+            )
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(ps * 8),
+                ps,
+                &[1u8][..],
+            ))
+            .dirty_tracking(true)
+            .build()
+            .unwrap();
+        gm.write_obj_at_addr(0x55u8, GuestAddress(ps)).unwrap();
+        gm.write_obj_at_addr(0x55u8, GuestAddress(ps * 2)).unwrap();
+        gm.get_and_clear_dirty_bitmap(0);
+        gm.get_and_clear_dirty_bitmap(1);
+
+        gm.advise(GuestAddress(0x0), ps * 4, Advice::WillNeed)
+            .unwrap();
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0]));
+
+        // Dropping private anonymous pages zeroes them, on both sides of the region boundary.
+        gm.advise(GuestAddress(ps), ps * 2, Advice::DontNeed)
+            .unwrap();
+        assert_eq!(gm.read_obj_from_addr::<u8>(GuestAddress(ps)).unwrap(), 0);
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(ps * 2)).unwrap(),
+            0
+        );
+        assert_eq!(gm.get_and_clear_dirty_bitmap(0), Some(vec![0b10]));
+        assert_eq!(gm.get_and_clear_dirty_bitmap(1), Some(vec![0b01]));
+
+        match gm.advise(GuestAddress(ps * 2), ps * 3, Advice::WillNeed) {
+            Err(Error::InvalidGuestAddress(a)) => assert_eq!(a, GuestAddress(ps * 4)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.advise(GuestAddress(ps * 8), ps, Advice::DontNeed) {
+            Err(Error::ReadOnlyRegion(a)) => assert_eq!(a, GuestAddress(ps * 8)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        gm.advise(GuestAddress(ps * 8), ps, Advice::WillNeed)
+            .unwrap();
+    }
+
+    #[test]
+    fn guard_pages() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps))
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps), ps).backing(RegionBacking::Anonymous),
+            )
+            .guard_pages(true)
+            .hotplug(true)
+            .build()
+            .unwrap();
+        let gm = gm.insert_region(GuestAddress(ps * 4), ps).unwrap();
+        for region in gm.regions.iter() {
+            assert_eq!(region.mapping.guard_size(), ps as usize);
+        }
+
+        // Guest accesses are unaffected, even across the boundary between two regions.
+        gm.write_obj_at_addr(0x1122_3344_5566_7788u64, GuestAddress(ps - 4))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(ps - 4)).unwrap(),
+            0x1122_3344_5566_7788
+        );
+        // The host mappings of adjacent regions are separated by the guard pages.
+        let first = gm.get_host_address(GuestAddress(0x0)).unwrap() as u64;
+        let second = gm.get_host_address(GuestAddress(ps)).unwrap() as u64;
+        assert!(first + ps * 2 <= second || second + ps * 2 <= first);
+
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), ps)]).unwrap();
+        assert_eq!(gm.regions[0].mapping.guard_size(), 0);
+    }
+
+    struct TestMmio(Mutex<Vec<u8>>);
+
+    impl MmioHandler for TestMmio {
+        fn read(&self, offset: u64, data: &mut [u8]) {
+            let offset = offset as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[offset..offset + data.len()]);
+        }
+
+        fn write(&self, offset: u64, data: &[u8]) {
+            let offset = offset as usize;
+            self.0.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn mmio_region() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let device = Arc::new(TestMmio(Mutex::new(vec![0u8; 0x100])));
+        let gm = gm
+            .insert_mmio_region(GuestAddress(0x2000), 0x100, device.clone())
+            .unwrap();
+
+        assert_eq!(
+            gm.write_at_addr(&[1, 2, 3, 4], GuestAddress(0x2010))
+                .unwrap(),
+            4
+        );
+        assert_eq!(&device.0.lock().unwrap()[0x10..0x14], &[1, 2, 3, 4]);
+        let mut buf = [0u8; 4];
+        gm.read_exact_at_addr(&mut buf, GuestAddress(0x2010))
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        // Accesses are truncated at the end of the MMIO region.
+        assert_eq!(gm.write_at_addr(&[5; 8], GuestAddress(0x20fc)).unwrap(), 4);
+
+        match gm.get_slice_at_addr(GuestAddress(0x2000), 4) {
+            Err(Error::MmioRegion(a)) => assert_eq!(a, GuestAddress(0x2000)),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        match gm.get_host_address(GuestAddress(0x20ff)) {
+            Err(Error::MmioRegion(a)) => assert_eq!(a, GuestAddress(0x20ff)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // Accessors that need host memory tell MMIO addresses apart from unmapped ones.
+        match gm.read_obj_from_addr::<u32>(GuestAddress(0x2010)) {
+            Err(Error::MmioRegion(a)) => assert_eq!(a, GuestAddress(0x2010)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let zero = File::open("/dev/zero").unwrap();
+        match gm.read_to_memory(GuestAddress(0x2000), &zero, 4) {
+            Err(Error::MmioRegion(a)) => assert_eq!(a, GuestAddress(0x2000)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match gm.read_at_addr(&mut buf, GuestAddress(0x2100)) {
+            Err(Error::InvalidGuestAddress(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(gm.memory_size(), 0x1000);
+
+        for &(base, size) in &[(0x0, 0x1000), (0x20f0, 0x100), (0x1f00, 0x200)] {
+            match gm.insert_mmio_region(GuestAddress(base), size, device.clone()) {
+                Err(Error::MemoryRegionOverlap) => {}
+                r => panic!("unexpected result: {:?}", r.map(|_| ())),
+            }
+        }
+        match gm.insert_region(GuestAddress(0x2000), 0x1000) {
+            Err(Error::MemoryRegionOverlap) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let gm = gm.remove_mmio_region(GuestAddress(0x2000)).unwrap();
+        match gm.read_at_addr(&mut buf, GuestAddress(0x2010)) {
+            Err(Error::InvalidGuestAddress(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
 }
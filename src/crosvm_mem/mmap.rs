@@ -6,15 +6,17 @@
 //! mmap object leaves scope.
 
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::io;
-use std::mem::size_of;
-use std::os::unix::io::AsRawFd;
+use std::mem::{size_of, zeroed, ManuallyDrop};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr::{copy_nonoverlapping, null_mut, read_unaligned, write_unaligned};
 
-use libc::{self, c_int, c_void, read, write};
+use libc::{self, c_int, c_void, off64_t, pread64, pwrite64, read, write};
 
 use super::data_init::DataInit;
+use super::shm::SharedMemory;
 use super::volatile_memory::*;
 use super::{errno, pagesize};
 
@@ -34,6 +36,18 @@ pub enum Error {
     ReadToMemory(io::Error),
     /// Reading from memory failed
     WriteFromMemory(io::Error),
+    /// Mappings with guard areas can't be resized.
+    ResizeGuarded,
+    /// Mappings of files or shared memory can only be grown by `resize_shared_memory`.
+    ResizeBacked,
+    /// The shared memory passed to `resize_shared_memory` isn't mapped at the given offset.
+    ResizeNotMapped,
+    /// Nothing is mapped at the given offset of an arena.
+    NotMapped(usize),
+    /// The protection at the given offset of an arena doesn't allow the access.
+    AccessDenied(usize),
+    /// Resizing the shared memory behind a mapping failed.
+    SharedMemoryResize(errno::Error),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -53,12 +67,25 @@ impl Display for Error {
             SystemCallFailed(e) => write!(f, "mmap system call failed: {}", e),
             ReadToMemory(e) => write!(f, "failed to read from file to memory: {}", e),
             WriteFromMemory(e) => write!(f, "failed to write from memory to file: {}", e),
+            ResizeGuarded => write!(f, "can't resize a mapping that has guard areas"),
+            ResizeBacked => write!(
+                f,
+                "can't grow a shared or file mapping without growing its backing"
+            ),
+            ResizeNotMapped => write!(f, "the shared memory isn't mapped at the given offset"),
+            NotMapped(offset) => write!(f, "nothing is mapped at arena offset {:#x}", offset),
+            AccessDenied(offset) => write!(
+                f,
+                "the protection at arena offset {:#x} doesn't allow the access",
+                offset
+            ),
+            SharedMemoryResize(e) => write!(f, "failed to resize shared memory: {}", e),
         }
     }
 }
 
 /// Memory access type for anonymous shared memory mapping.
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Protection(c_int);
 impl Protection {
     /// Returns Protection allowing no access.
@@ -90,6 +117,18 @@ impl Protection {
     pub fn set_write(self) -> Protection {
         Protection(self.0 | libc::PROT_WRITE)
     }
+
+    /// Returns true if read access is allowed.
+    #[inline(always)]
+    pub fn is_readable(self) -> bool {
+        self.0 & libc::PROT_READ != 0
+    }
+
+    /// Returns true if write access is allowed.
+    #[inline(always)]
+    pub fn is_writable(self) -> bool {
+        self.0 & libc::PROT_WRITE != 0
+    }
 }
 
 impl From<c_int> for Protection {
@@ -104,6 +143,62 @@ impl Into<c_int> for Protection {
     }
 }
 
+// Not exported by older versions of libc.
+const MADV_COLD: c_int = 20;
+const MADV_PAGEOUT: c_int = 21;
+
+/// Advice about the use of a range of a memory mapping, passed to the kernel with `madvise`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Advice {
+    /// The range will be accessed soon, so it should be read ahead (`MADV_WILLNEED`).
+    WillNeed,
+    /// The range won't be accessed soon, so its pages can be freed (`MADV_DONTNEED`). Private
+    /// anonymous pages read as zeroes afterwards.
+    DontNeed,
+    /// The pages of the range can be freed lazily, when there is memory pressure (`MADV_FREE`).
+    /// Only valid for private anonymous mappings.
+    Free,
+    /// Back the range with transparent huge pages (`MADV_HUGEPAGE`).
+    HugePage,
+    /// Don't back the range with transparent huge pages (`MADV_NOHUGEPAGE`).
+    NoHugePage,
+    /// Let KSM merge the pages of the range with identical ones (`MADV_MERGEABLE`).
+    Mergeable,
+    /// Undo `Mergeable` (`MADV_UNMERGEABLE`).
+    Unmergeable,
+    /// Deactivate the pages of the range, so they are reclaimed first (`MADV_COLD`).
+    Cold,
+    /// Reclaim the pages of the range right away (`MADV_PAGEOUT`).
+    PageOut,
+    /// Include the range in core dumps (`MADV_DODUMP`).
+    DoDump,
+    /// Exclude the range from core dumps (`MADV_DONTDUMP`).
+    DontDump,
+}
+
+impl Advice {
+    /// Returns true if the contents of the range may read as zeroes after this advice.
+    pub fn discards_contents(self) -> bool {
+        matches!(self, Advice::DontNeed | Advice::Free)
+    }
+
+    fn flag(self) -> c_int {
+        match self {
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            Advice::DontNeed => libc::MADV_DONTNEED,
+            Advice::Free => libc::MADV_FREE,
+            Advice::HugePage => libc::MADV_HUGEPAGE,
+            Advice::NoHugePage => libc::MADV_NOHUGEPAGE,
+            Advice::Mergeable => libc::MADV_MERGEABLE,
+            Advice::Unmergeable => libc::MADV_UNMERGEABLE,
+            Advice::Cold => MADV_COLD,
+            Advice::PageOut => MADV_PAGEOUT,
+            Advice::DoDump => libc::MADV_DODUMP,
+            Advice::DontDump => libc::MADV_DONTDUMP,
+        }
+    }
+}
+
 /// Validates that `offset`..`offset+range_size` lies within the bounds of a memory mapping of
 /// `mmap_size` bytes.  Also checks for any overflow.
 fn validate_includes_range(mmap_size: usize, offset: usize, range_size: usize) -> Result<()> {
@@ -119,6 +214,31 @@ fn validate_includes_range(mmap_size: usize, offset: usize, range_size: usize) -
     }
 }
 
+/// Converts `file_offset` to an `off64_t`, making sure that the `count` bytes starting there are
+/// all addressable.
+fn file_offset_to_off64(file_offset: u64, count: usize) -> Result<off64_t> {
+    file_offset
+        .checked_add(count as u64)
+        .filter(|&end| end <= off64_t::MAX as u64)
+        .map(|_| file_offset as off64_t)
+        .ok_or(Error::InvalidOffset)
+}
+
+/// Changes the protection of the `len` bytes at `offset` bytes from `addr` to `prot`.
+///
+/// Safe when `addr + offset`..`addr + offset + len` is a range of mapped memory owned by the
+/// caller, whose contents don't need to stay accessible with the old protection.
+unsafe fn mprotect_range(addr: *mut u8, offset: usize, len: usize, prot: Protection) -> Result<()> {
+    if offset % pagesize() != 0 {
+        return Err(Error::NotPageAligned);
+    }
+    let ret = libc::mprotect(addr.add(offset) as *mut libc::c_void, len, prot.into());
+    if ret == -1 {
+        return Err(Error::SystemCallFailed(errno::Error::last()));
+    }
+    Ok(())
+}
+
 /// A range of memory that can be msynced, for abstracting over different types of memory mappings.
 ///
 /// Safe when implementers guarantee `ptr`..`ptr+size` is an mmaped region owned by this object that
@@ -161,6 +281,16 @@ impl dyn MappedRegion {
 pub struct MemoryMapping {
     addr: *mut u8,
     size: usize,
+    // Size of the inaccessible guard areas reserved right before and right after the mapping.
+    guard_size: usize,
+    // Protection that all of the mapping allows, which is less than what some pages allow once
+    // the protection of part of the mapping was changed.
+    prot: Protection,
+    // The file descriptor that is mapped and the offset of the mapping in it, or `None` for
+    // anonymous mappings.
+    fd: Option<(RawFd, u64)>,
+    // Whether the mapping was created with `MAP_SHARED`.
+    shared: bool,
 }
 
 // Send and Sync aren't automatically inherited for the raw address pointer.
@@ -198,6 +328,135 @@ impl MemoryMapping {
         }
     }
 
+    /// Creates an anonymous shared mapping of `size` bytes with `prot` protection, surrounded by
+    /// `guard_size` bytes of inaccessible memory on each side.
+    ///
+    /// Accesses that run past either end of the mapping fault instead of touching other mappings.
+    /// The guard areas are unmapped together with the mapping.
+    ///
+    /// # Arguments
+    /// * `size` - Size of memory region in bytes.
+    /// * `guard_size` - Page aligned size of each of the guard areas in bytes.
+    /// * `prot` - Protection (e.g. readable/writable) of the memory region.
+    pub fn new_guarded(size: usize, guard_size: usize, prot: Protection) -> Result<MemoryMapping> {
+        MemoryMapping::new_guarded_flags(
+            size,
+            guard_size,
+            pagesize(),
+            libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+            None,
+            prot,
+        )
+    }
+
+    /// Same as `new_guarded`, but maps the `size` bytes starting at `offset` bytes of the given
+    /// `fd`.
+    ///
+    /// # Arguments
+    /// * `fd` - File descriptor to mmap from.
+    /// * `size` - Size of memory region in bytes.
+    /// * `offset` - Offset in bytes from the beginning of `fd` to start the mmap.
+    /// * `guard_size` - Page aligned size of each of the guard areas in bytes.
+    /// * `prot` - Protection (e.g. readable/writable) of the memory region.
+    pub fn from_fd_offset_guarded(
+        fd: &dyn AsRawFd,
+        size: usize,
+        offset: u64,
+        guard_size: usize,
+        prot: Protection,
+    ) -> Result<MemoryMapping> {
+        MemoryMapping::new_guarded_flags(
+            size,
+            guard_size,
+            pagesize(),
+            libc::MAP_SHARED,
+            Some((fd, offset)),
+            prot,
+        )
+    }
+
+    /// Maps `size` bytes with `prot` protection, passing `flags` directly to mmap, and surrounds
+    /// the mapping with `guard_size` bytes of inaccessible memory on each side. If `fd` is `None`,
+    /// `flags` must include `MAP_ANONYMOUS`.
+    ///
+    /// The mapping starts at a multiple of `align`, which must be a power of two and at least the
+    /// page size, e.g. the size of the huge pages of a hugetlbfs `fd`. When `guard_size` is 0, no
+    /// guard areas are reserved and the mapping is created the same way as by the other
+    /// constructors.
+    pub(crate) fn new_guarded_flags(
+        size: usize,
+        guard_size: usize,
+        align: usize,
+        flags: c_int,
+        fd: Option<(&dyn AsRawFd, u64)>,
+        prot: Protection,
+    ) -> Result<MemoryMapping> {
+        if guard_size == 0 {
+            // This is safe because we are creating a mapping in a place not already used by any
+            // other area in this process.
+            return unsafe { MemoryMapping::try_mmap(None, size, prot.into(), flags, fd) };
+        }
+        if guard_size % align != 0 {
+            return Err(Error::NotPageAligned);
+        }
+
+        // Reserve enough address space to find a range that is aligned as requested, with room for
+        // the guard areas on each side.
+        let reserved_size = guard_size
+            .checked_mul(2)
+            .and_then(|s| s.checked_add(size))
+            .and_then(|s| s.checked_add(align - pagesize()))
+            .ok_or(Error::InvalidAddress)?;
+        // This is safe because we are creating an anonymous mapping in a place not already used by
+        // any other area in this process.
+        let reservation = unsafe {
+            MemoryMapping::try_mmap(
+                None,
+                reserved_size,
+                libc::PROT_NONE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                None,
+            )?
+        };
+        let reserved = reservation.addr as usize;
+        let start = (reserved + guard_size + align - 1) & !(align - 1);
+        let end = start + size;
+        std::mem::forget(reservation);
+
+        // Trim the reservation down to the mapping and its guard areas. This is safe because the
+        // trimmed ranges are part of the reservation, which isn't used by anything yet.
+        let reservation = unsafe {
+            if start - guard_size > reserved {
+                libc::munmap(reserved as *mut c_void, start - guard_size - reserved);
+            }
+            if reserved + reserved_size > end + guard_size {
+                libc::munmap(
+                    (end + guard_size) as *mut c_void,
+                    reserved + reserved_size - end - guard_size,
+                );
+            }
+            MemoryMapping {
+                addr: (start - guard_size) as *mut u8,
+                size: size + 2 * guard_size,
+                guard_size: 0,
+                prot: Protection::none(),
+                fd: None,
+                shared: false,
+            }
+        };
+
+        // This is safe because the range is part of the reservation, which is only ever accessed
+        // through the mapping placed over it. If mapping fails, the reservation is dropped and
+        // unmapped as a whole.
+        let mmap = unsafe {
+            MemoryMapping::try_mmap(Some(start as *mut u8), size, prot.into(), flags, fd)?
+        };
+        std::mem::forget(reservation);
+        let mut mmap = mmap;
+        mmap.guard_size = guard_size;
+        Ok(mmap)
+    }
+
     /// Maps the first `size` bytes of the given `fd` as read/write.
     ///
     /// # Arguments
@@ -239,7 +498,7 @@ impl MemoryMapping {
     /// * `offset` - Offset in bytes from the beginning of `fd` to start the mmap.
     /// * `flags` - flags passed directly to mmap.
     /// * `prot` - Protection (e.g. readable/writable) of the memory region.
-    fn from_fd_offset_flags(
+    pub(crate) fn from_fd_offset_flags(
         fd: &dyn AsRawFd,
         size: usize,
         offset: u64,
@@ -338,22 +597,23 @@ impl MemoryMapping {
             None => null_mut(),
         };
         // If fd is provided, validate fd offset is within bounds
-        let (fd, offset) = match fd {
+        let fd = match fd {
             Some((fd, offset)) => {
                 if offset > libc::off_t::max_value() as u64 {
                     return Err(Error::InvalidOffset);
                 }
-                (fd.as_raw_fd(), offset as libc::off_t)
+                Some((fd.as_raw_fd(), offset))
             }
-            None => (-1, 0),
+            None => None,
         };
-        let addr = libc::mmap(addr, size, prot, flags, fd, offset);
+        let (raw_fd, offset) = fd.unwrap_or((-1, 0));
+        let addr = libc::mmap(addr, size, prot, flags, raw_fd, offset as libc::off_t);
         if addr == libc::MAP_FAILED {
             return Err(Error::SystemCallFailed(errno::Error::last()));
         }
         // This is safe because we call madvise with a valid address and size, and we check the
         // return value. We only warn about an error because failure here is not fatal to the mmap.
-        if libc::madvise(addr, size, libc::MADV_DONTDUMP) == -1 {
+        if libc::madvise(addr, size, Advice::DontDump.flag()) == -1 {
             // Commenting the warn because it wasn't clear where it came from when hastily
             // bringing over the code from crosvm.
             // warn!(
@@ -364,9 +624,166 @@ impl MemoryMapping {
         Ok(MemoryMapping {
             addr: addr as *mut u8,
             size,
+            guard_size: 0,
+            prot: Protection::from(prot),
+            fd,
+            shared: flags & libc::MAP_SHARED != 0,
         })
     }
 
+    /// Grows or shrinks the mapping to `new_size` bytes with `mremap`.
+    ///
+    /// Shrinking always happens in place. Growing happens in place if the address space right
+    /// after the mapping is free, and otherwise fails unless `may_move` is true, in which case the
+    /// whole mapping may be moved to a new address. Either way its contents are preserved. Taking
+    /// `self` mutably guarantees that there are no outstanding `VolatileSlice`s or `VolatileRef`s
+    /// into the mapping that could be left dangling, but pointers obtained from `as_ptr` must not
+    /// be used after this returns.
+    ///
+    /// Only private anonymous mappings can be grown, since their pages are created on demand when
+    /// the added range is accessed. Shared mappings, including the anonymous ones created by
+    /// `new`, and file mappings are backed by a file of a fixed size, so growing them fails with
+    /// `ResizeBacked`; grow memfd-backed mappings with `resize_shared_memory` instead. Mappings
+    /// created with guard areas can't be resized.
+    pub fn resize(&mut self, new_size: usize, may_move: bool) -> Result<()> {
+        if new_size > self.size && (self.shared || self.fd.is_some()) {
+            return Err(Error::ResizeBacked);
+        }
+        self.remap(new_size, may_move)
+    }
+
+    fn remap(&mut self, new_size: usize, may_move: bool) -> Result<()> {
+        if self.guard_size != 0 {
+            return Err(Error::ResizeGuarded);
+        }
+        let flags = if may_move { libc::MREMAP_MAYMOVE } else { 0 };
+        // This is safe because we own the mapping, and nothing borrows it while `self` is borrowed
+        // mutably. The kernel either resizes it as a whole, or leaves it untouched on failure.
+        let addr = unsafe { libc::mremap(self.addr as *mut c_void, self.size, new_size, flags) };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::SystemCallFailed(errno::Error::last()));
+        }
+        self.addr = addr as *mut u8;
+        self.size = new_size;
+        Ok(())
+    }
+
+    // Returns true if the mapping maps the file behind `fd`, starting at `offset` bytes into it.
+    fn maps_file(&self, fd: &dyn AsRawFd, offset: u64) -> bool {
+        let (mapped_fd, mapped_offset) = match self.fd {
+            Some(mapped) => mapped,
+            None => return false,
+        };
+        // The mapping doesn't keep its fd open, so compare the files rather than the fds.
+        let file_id = |fd: RawFd| {
+            // Safe because we pass a pointer to a `stat` that lives on the stack, and check the
+            // result.
+            let mut st: libc::stat64 = unsafe { zeroed() };
+            if unsafe { libc::fstat64(fd, &mut st) } < 0 {
+                return None;
+            }
+            Some((st.st_dev, st.st_ino))
+        };
+        mapped_offset == offset
+            && matches!(file_id(mapped_fd), Some(id) if Some(id) == file_id(fd.as_raw_fd()))
+    }
+
+    /// Same as `resize`, for a mapping of `shm` that starts at `offset` bytes into it, and also
+    /// resizes `shm` to match. Fails with `ResizeNotMapped` if the mapping isn't a mapping of
+    /// `shm` at `offset`.
+    ///
+    /// When growing, `shm` is grown first if the mapping would extend past its end, which fails
+    /// if `shm` has the grow seal. When shrinking, `shm` is truncated to the new end of the mapping
+    /// only if the mapping used to extend to the end of `shm`, and `shm` doesn't have the shrink
+    /// seal. Other mappings of the truncated range get signaled with SIGBUS if they access it.
+    pub fn resize_shared_memory(
+        &mut self,
+        shm: &mut SharedMemory,
+        offset: u64,
+        new_size: usize,
+        may_move: bool,
+    ) -> Result<()> {
+        if !self.maps_file(shm, offset) {
+            return Err(Error::ResizeNotMapped);
+        }
+        let old_end = offset
+            .checked_add(self.size as u64)
+            .ok_or(Error::InvalidOffset)?;
+        let new_end = offset
+            .checked_add(new_size as u64)
+            .ok_or(Error::InvalidOffset)?;
+        if new_end > shm.size() {
+            shm.set_size(new_end).map_err(Error::SharedMemoryResize)?;
+        }
+        self.remap(new_size, may_move)?;
+        if new_end < old_end && old_end >= shm.size() {
+            // Not every file supports seals, in which case it can always be shrunk.
+            let shrink_sealed = matches!(shm.get_seals(), Ok(seals) if seals.shrink_seal());
+            if !shrink_sealed {
+                shm.set_size(new_end).map_err(Error::SharedMemoryResize)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the size of each of the inaccessible guard areas around the mapping, which is 0 if
+    /// it wasn't created with guard areas.
+    pub fn guard_size(&self) -> usize {
+        self.guard_size
+    }
+
+    /// Gives the kernel `advice` about the `len` bytes starting at `offset`.
+    ///
+    /// `offset` must be page aligned, and the range is extended to the end of the page that
+    /// contains its last byte.
+    pub fn advise(&self, offset: usize, len: usize, advice: Advice) -> Result<()> {
+        self.range_end(offset, len)?;
+        if offset % pagesize() != 0 {
+            return Err(Error::NotPageAligned);
+        }
+        // This is safe because the range has been validated to lie within the mapping. Advice
+        // that discards pages only affects memory owned by this mapping, which is accessed through
+        // volatile operations that can cope with its contents changing.
+        let ret = unsafe {
+            libc::madvise(
+                self.addr.add(offset) as *mut libc::c_void,
+                len,
+                advice.flag(),
+            )
+        };
+        if ret == -1 {
+            return Err(Error::SystemCallFailed(errno::Error::last()));
+        }
+        Ok(())
+    }
+
+    /// Changes the protection of the `len` bytes starting at `offset` to `prot`.
+    ///
+    /// `offset` must be page aligned, and the range is extended to the end of the page that
+    /// contains its last byte. Taking `self` mutably guarantees that there are no outstanding
+    /// `VolatileSlice`s or `VolatileRef`s into the mapping.
+    ///
+    /// # Safety
+    /// The accessors of the mapping don't check its protection, so the caller must make sure that
+    /// the range isn't accessed in ways that `prot` doesn't allow afterwards, which would fault.
+    pub unsafe fn set_protection(
+        &mut self,
+        offset: usize,
+        len: usize,
+        prot: Protection,
+    ) -> Result<()> {
+        let end = self.range_end(offset, len)?;
+        // The range has been validated to lie within the mapping.
+        mprotect_range(self.addr, offset, len, prot)?;
+        self.prot = if offset == 0 && end == self.size {
+            prot
+        } else {
+            let (old, new): (c_int, c_int) = (self.prot.into(), prot.into());
+            Protection::from(old & new)
+        };
+        Ok(())
+    }
+
     /// Calls msync with MS_SYNC on the mapping.
     pub fn msync(&self) -> Result<()> {
         // This is safe since we use the exact address and length of a known
@@ -618,6 +1035,106 @@ impl MemoryMapping {
         Ok(())
     }
 
+    /// Same as `read_to_memory`, but reads from `src` starting at `file_offset` with `pread`,
+    /// leaving the file position of `src` untouched. Multiple threads can use this to read from
+    /// the same file concurrently.
+    ///
+    /// # Arguments
+    /// * `mem_offset` - Begin writing memory at this offset.
+    /// * `src` - Read from `src` to memory.
+    /// * `file_offset` - Begin reading `src` at this offset.
+    /// * `count` - Read `count` bytes from `src` to memory.
+    pub fn read_to_memory_at(
+        &self,
+        mut mem_offset: usize,
+        src: &dyn AsRawFd,
+        mut file_offset: u64,
+        mut count: usize,
+    ) -> Result<()> {
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size()))?;
+        while count > 0 {
+            let offset = file_offset_to_off64(file_offset, count)?;
+            // The check above ensures that no memory outside this slice will get accessed by this
+            // read call.
+            match unsafe {
+                pread64(
+                    src.as_raw_fd(),
+                    self.as_ptr().add(mem_offset) as *mut c_void,
+                    count,
+                    offset,
+                )
+            } {
+                0 => {
+                    return Err(Error::ReadToMemory(io::Error::from(
+                        io::ErrorKind::UnexpectedEof,
+                    )))
+                }
+                r if r < 0 => return Err(Error::ReadToMemory(io::Error::last_os_error())),
+                ret => {
+                    let bytes_read = ret as usize;
+                    match count.checked_sub(bytes_read) {
+                        Some(count_remaining) => count = count_remaining,
+                        None => break,
+                    }
+                    mem_offset += bytes_read;
+                    file_offset += bytes_read as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `write_from_memory`, but writes to `dst` starting at `file_offset` with `pwrite`,
+    /// leaving the file position of `dst` untouched. Multiple threads can use this to write to
+    /// the same file concurrently.
+    ///
+    /// # Arguments
+    /// * `mem_offset` - Begin reading memory from this offset.
+    /// * `dst` - Write from memory to `dst`.
+    /// * `file_offset` - Begin writing `dst` at this offset.
+    /// * `count` - Read `count` bytes from memory to `dst`.
+    pub fn write_from_memory_at(
+        &self,
+        mut mem_offset: usize,
+        dst: &dyn AsRawFd,
+        mut file_offset: u64,
+        mut count: usize,
+    ) -> Result<()> {
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size()))?;
+        while count > 0 {
+            let offset = file_offset_to_off64(file_offset, count)?;
+            // The check above ensures that no memory outside this slice will get accessed by this
+            // write call.
+            match unsafe {
+                pwrite64(
+                    dst.as_raw_fd(),
+                    self.as_ptr().add(mem_offset) as *const c_void,
+                    count,
+                    offset,
+                )
+            } {
+                0 => {
+                    return Err(Error::WriteFromMemory(io::Error::from(
+                        io::ErrorKind::WriteZero,
+                    )))
+                }
+                ret if ret < 0 => return Err(Error::WriteFromMemory(io::Error::last_os_error())),
+                ret => {
+                    let bytes_written = ret as usize;
+                    match count.checked_sub(bytes_written) {
+                        Some(count_remaining) => count = count_remaining,
+                        None => break,
+                    }
+                    mem_offset += bytes_written;
+                    file_offset += bytes_written as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Uses madvise to tell the kernel to remove the specified range.  Subsequent reads
     /// to the pages in the range will return zero bytes.
     pub fn remove_range(&self, mem_offset: usize, count: usize) -> Result<()> {
@@ -684,10 +1201,13 @@ impl VolatileMemory for MemoryMapping {
 
 impl Drop for MemoryMapping {
     fn drop(&mut self) {
-        // This is safe because we mmap the area at addr ourselves, and nobody
-        // else is holding a reference to it.
+        // This is safe because we mmap the area at addr, and the guard areas around it, ourselves,
+        // and nobody else is holding a reference to it.
         unsafe {
-            libc::munmap(self.addr as *mut libc::c_void, self.size);
+            libc::munmap(
+                self.addr.sub(self.guard_size) as *mut libc::c_void,
+                self.size + 2 * self.guard_size,
+            );
         }
     }
 }
@@ -697,6 +1217,64 @@ impl Drop for MemoryMapping {
 pub struct MemoryMappingArena {
     addr: *mut u8,
     size: usize,
+    // The ranges mapped with the `add_*` methods, keyed by their offsets. They never overlap.
+    mappings: BTreeMap<usize, ArenaMapping>,
+}
+
+/// Describes a range of a `MemoryMappingArena` that was mapped with one of its `add_*` methods.
+///
+/// Sizes are rounded up to a whole number of pages, because that's what the kernel maps. When part
+/// of a range is replaced, removed, or gets a different protection, the rest of it is described
+/// separately.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ArenaMapping {
+    offset: usize,
+    size: usize,
+    prot: Protection,
+    fd: Option<(RawFd, u64)>,
+}
+
+impl ArenaMapping {
+    /// Returns the offset of the range from the start of the arena, in bytes.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the size of the range in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the protection of the range.
+    pub fn protection(&self) -> Protection {
+        self.prot
+    }
+
+    /// Returns the file descriptor that is mapped in the range, and the offset in it that the
+    /// range starts at, or `None` for anonymous mappings. The arena doesn't keep the file
+    /// descriptor open, so it may have been closed since.
+    pub fn fd(&self) -> Option<(RawFd, u64)> {
+        self.fd
+    }
+
+    fn end(&self) -> usize {
+        self.offset + self.size
+    }
+
+    // Splits the range at `offset`, which must be inside it, and returns the part after it.
+    fn split_off(&mut self, offset: usize) -> ArenaMapping {
+        let delta = offset - self.offset;
+        let tail = ArenaMapping {
+            offset,
+            size: self.size - delta,
+            prot: self.prot,
+            fd: self
+                .fd
+                .map(|(fd, fd_offset)| (fd, fd_offset + delta as u64)),
+        };
+        self.size = delta;
+        tail
+    }
 }
 
 // Send and Sync aren't automatically inherited for the raw address pointer.
@@ -712,8 +1290,34 @@ impl MemoryMappingArena {
     /// # Arguments
     /// * `size` - Size of memory region in bytes.
     pub fn new(size: usize) -> Result<MemoryMappingArena> {
-        // Reserve the arena's memory using an anonymous read-only mmap.
-        MemoryMapping::new_protection(size, Protection::none().set_read()).map(From::from)
+        // Reserve the arena's memory using an anonymous read-only mmap, which isn't tracked as
+        // mapped.
+        MemoryMapping::new_protection(size, Protection::none().set_read())
+            .map(MemoryMappingArena::take_ownership)
+    }
+
+    // Returns an arena that owns the memory of `mmap`, without tracking any of it as mapped.
+    fn take_ownership(mmap: MemoryMapping) -> MemoryMappingArena {
+        let addr = mmap.as_ptr();
+        let size = mmap.size();
+
+        // The arena only covers the mapping itself, so release its guard areas. This is safe
+        // because they are owned by the mapping and never accessed.
+        if mmap.guard_size != 0 {
+            unsafe {
+                libc::munmap(addr.sub(mmap.guard_size) as *mut c_void, mmap.guard_size);
+                libc::munmap(addr.add(size) as *mut c_void, mmap.guard_size);
+            }
+        }
+
+        // Forget the original mapping because the `MemoryMappingArena` will take care of calling
+        // `munmap` when it is dropped.
+        std::mem::forget(mmap);
+        MemoryMappingArena {
+            addr,
+            size,
+            mappings: BTreeMap::new(),
+        }
     }
 
     /// Anonymously maps `size` bytes at `offset` bytes from the start of the arena.
@@ -724,7 +1328,9 @@ impl MemoryMappingArena {
     /// * `size` - Size of memory region in bytes.
     /// * `fd` - File descriptor to mmap from.
     pub fn add_anon(&mut self, offset: usize, size: usize) -> Result<()> {
-        self.try_add(offset, size, Protection::read_write(), None)
+        self.try_add(offset, size, Protection::read_write(), None)?;
+        self.track(offset, size, Protection::read_write(), None);
+        Ok(())
     }
 
     /// Maps `size` bytes from the start of the given `fd` at `offset` bytes from
@@ -774,7 +1380,9 @@ impl MemoryMappingArena {
         fd_offset: u64,
         prot: Protection,
     ) -> Result<()> {
-        self.try_add(offset, size, prot, Some((fd, fd_offset)))
+        self.try_add(offset, size, prot, Some((fd, fd_offset)))?;
+        self.track(offset, size, prot, Some((fd.as_raw_fd(), fd_offset)));
+        Ok(())
     }
 
     /// Helper method that calls appropriate MemoryMapping constructor and adds
@@ -808,9 +1416,165 @@ impl MemoryMappingArena {
 
         // This mapping will get automatically removed when we drop the whole arena.
         std::mem::forget(mmap);
+
+        // Whatever was mapped in the range before has been replaced.
+        let end = self.page_range_end(offset, size);
+        self.forget_range(offset, end);
+        Ok(())
+    }
+
+    // Records the range mapped by a successful call to `try_add`.
+    fn track(&mut self, offset: usize, size: usize, prot: Protection, fd: Option<(RawFd, u64)>) {
+        let size = self.page_range_end(offset, size) - offset;
+        self.mappings.insert(
+            offset,
+            ArenaMapping {
+                offset,
+                size,
+                prot,
+                fd,
+            },
+        );
+    }
+
+    /// Returns the ranges mapped with the `add_*` methods that are still in place, in order of
+    /// their offsets.
+    pub fn mappings(&self) -> impl Iterator<Item = ArenaMapping> + '_ {
+        self.mappings.values().copied()
+    }
+
+    /// Returns the range mapped with one of the `add_*` methods that contains the byte at `offset`
+    /// bytes from the start of the arena, if any.
+    pub fn lookup(&self, offset: usize) -> Option<ArenaMapping> {
+        self.mappings
+            .range(..=offset)
+            .next_back()
+            .map(|(_, mapping)| *mapping)
+            .filter(|mapping| offset < mapping.end())
+    }
+
+    /// Writes a slice to the arena at the specified offset, like `MemoryMapping::write_slice`.
+    ///
+    /// Returns `NotMapped` if part of the range isn't mapped with one of the `add_*` methods, and
+    /// `AccessDenied` if part of it isn't writable, without writing anything.
+    pub fn write_slice(&self, buf: &[u8], offset: usize) -> Result<usize> {
+        let size_past_offset = self.size.checked_sub(offset).ok_or(Error::InvalidAddress)?;
+        let count = min(size_past_offset, buf.len());
+        self.check_access(offset, count, Protection::is_writable)?;
+        self.as_mapping().write_slice(buf, offset)
+    }
+
+    /// Reads to a slice from the arena at the specified offset, like `MemoryMapping::read_slice`.
+    ///
+    /// Returns `NotMapped` if part of the range isn't mapped with one of the `add_*` methods, and
+    /// `AccessDenied` if part of it isn't readable, without reading anything.
+    pub fn read_slice(&self, buf: &mut [u8], offset: usize) -> Result<usize> {
+        let size_past_offset = self.size.checked_sub(offset).ok_or(Error::InvalidAddress)?;
+        let count = min(size_past_offset, buf.len());
+        self.check_access(offset, count, Protection::is_readable)?;
+        self.as_mapping().read_slice(buf, offset)
+    }
+
+    /// Writes an object to the arena at the specified offset, like `MemoryMapping::write_obj`.
+    /// Fails like `write_slice` if the range isn't mapped and writable.
+    pub fn write_obj<T: DataInit>(&self, val: T, offset: usize) -> Result<()> {
+        validate_includes_range(self.size, offset, size_of::<T>())?;
+        self.check_access(offset, size_of::<T>(), Protection::is_writable)?;
+        self.as_mapping().write_obj(val, offset)
+    }
+
+    /// Reads an object from the arena at the specified offset, like `MemoryMapping::read_obj`.
+    /// Fails like `read_slice` if the range isn't mapped and readable.
+    pub fn read_obj<T: DataInit>(&self, offset: usize) -> Result<T> {
+        validate_includes_range(self.size, offset, size_of::<T>())?;
+        self.check_access(offset, size_of::<T>(), Protection::is_readable)?;
+        self.as_mapping().read_obj(offset)
+    }
+
+    /// Reads data from a file descriptor and writes it to the arena, like
+    /// `MemoryMapping::read_to_memory`. Fails like `write_slice` if the range isn't mapped and
+    /// writable.
+    pub fn read_to_memory(&self, mem_offset: usize, src: &dyn AsRawFd, count: usize) -> Result<()> {
+        validate_includes_range(self.size, mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size))?;
+        self.check_access(mem_offset, count, Protection::is_writable)?;
+        self.as_mapping().read_to_memory(mem_offset, src, count)
+    }
+
+    /// Writes data from the arena to a file descriptor, like `MemoryMapping::write_from_memory`.
+    /// Fails like `read_slice` if the range isn't mapped and readable.
+    pub fn write_from_memory(
+        &self,
+        mem_offset: usize,
+        dst: &dyn AsRawFd,
+        count: usize,
+    ) -> Result<()> {
+        validate_includes_range(self.size, mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size))?;
+        self.check_access(mem_offset, count, Protection::is_readable)?;
+        self.as_mapping().write_from_memory(mem_offset, dst, count)
+    }
+
+    // Checks that the `count` bytes at `offset` are covered by mappings whose protection satisfies
+    // `allowed`.
+    fn check_access<F>(&self, offset: usize, count: usize, allowed: F) -> Result<()>
+    where
+        F: Fn(Protection) -> bool,
+    {
+        let end = offset + count;
+        let mut pos = offset;
+        while pos < end {
+            let mapping = self.lookup(pos).ok_or(Error::NotMapped(pos))?;
+            if !allowed(mapping.prot) {
+                return Err(Error::AccessDenied(pos));
+            }
+            pos = mapping.end();
+        }
         Ok(())
     }
 
+    // Returns a `MemoryMapping` that covers the whole arena, to share its accessors. It must not be
+    // dropped, because the arena stays the owner of the memory.
+    fn as_mapping(&self) -> ManuallyDrop<MemoryMapping> {
+        ManuallyDrop::new(MemoryMapping {
+            addr: self.addr,
+            size: self.size,
+            guard_size: 0,
+            prot: Protection::read_write(),
+            fd: None,
+            shared: true,
+        })
+    }
+
+    // Returns the end of the range of pages that the `size` bytes at `offset` touch, limited to the
+    // size of the arena.
+    fn page_range_end(&self, offset: usize, size: usize) -> usize {
+        let ps = pagesize();
+        let end = offset.saturating_add(size);
+        min(end.saturating_add(ps - 1) / ps * ps, self.size)
+    }
+
+    // Makes sure that no tracked range crosses `offset`, by splitting the one that does.
+    fn split_at(&mut self, offset: usize) {
+        if let Some(mut mapping) = self.lookup(offset) {
+            if mapping.offset != offset {
+                let tail = mapping.split_off(offset);
+                self.mappings.insert(mapping.offset, mapping);
+                self.mappings.insert(tail.offset, tail);
+            }
+        }
+    }
+
+    // Stops tracking the parts of ranges between `offset` and `end`.
+    fn forget_range(&mut self, offset: usize, end: usize) {
+        self.split_at(offset);
+        self.split_at(end);
+        let inside: Vec<usize> = self.mappings.range(offset..end).map(|(&o, _)| o).collect();
+        for o in inside {
+            self.mappings.remove(&o);
+        }
+    }
+
     /// Removes `size` bytes at `offset` bytes from the start of the arena. `offset` must be page
     /// aligned.
     ///
@@ -820,6 +1584,27 @@ impl MemoryMappingArena {
     pub fn remove(&mut self, offset: usize, size: usize) -> Result<()> {
         self.try_add(offset, size, Protection::read(), None)
     }
+
+    /// Changes the protection of `size` bytes at `offset` bytes from the start of the arena to
+    /// `prot`, without replacing what is mapped there. `offset` must be page aligned.
+    ///
+    /// # Arguments
+    /// * `offset` - Page aligned offset into the arena in bytes.
+    /// * `size` - Size of the range in bytes.
+    /// * `prot` - Protection (e.g. readable/writable) of the range.
+    pub fn set_protection(&mut self, offset: usize, size: usize, prot: Protection) -> Result<()> {
+        validate_includes_range(self.size(), offset, size)?;
+        // This is safe since the range has been validated, and the arena owns all of it.
+        unsafe { mprotect_range(self.addr, offset, size, prot)? };
+
+        let end = self.page_range_end(offset, size);
+        self.split_at(offset);
+        self.split_at(end);
+        for mapping in self.mappings.range_mut(offset..end).map(|(_, m)| m) {
+            mapping.prot = prot;
+        }
+        Ok(())
+    }
 }
 
 // Safe because the pointer and size point to a memory range owned by this MemoryMappingArena that
@@ -834,15 +1619,43 @@ unsafe impl MappedRegion for MemoryMappingArena {
     }
 }
 
+impl VolatileMemory for MemoryMappingArena {
+    /// Returns a slice of the arena, which must be fully covered by readable and writable mappings
+    /// added with one of the `add_*` methods. Otherwise, the error reports the first offset that
+    /// isn't mapped, or is mapped without read or write access.
+    fn get_slice(&self, offset: usize, count: usize) -> VolatileMemoryResult<VolatileSlice<'_>> {
+        let mem_end = calc_offset(offset, count)?;
+        if mem_end > self.size {
+            return Err(VolatileMemoryError::OutOfBounds { addr: mem_end });
+        }
+        // Slices can be both read and written through.
+        self.check_access(offset, count, |prot| {
+            prot.is_readable() && prot.is_writable()
+        })
+        .map_err(|e| match e {
+            Error::NotMapped(addr) => VolatileMemoryError::NotMapped { addr },
+            Error::AccessDenied(addr) => VolatileMemoryError::AccessDenied { addr },
+            e => unreachable!("unexpected arena access error: {}", e),
+        })?;
+
+        // Safe because we checked that offset + count is within the arena, that all of it is
+        // mapped read/write, and we only ever hand out volatile accessors.
+        Ok(unsafe { VolatileSlice::from_raw_parts(self.addr.add(offset), count) })
+    }
+}
+
 impl From<MemoryMapping> for MemoryMappingArena {
     fn from(mmap: MemoryMapping) -> Self {
-        let addr = mmap.as_ptr();
-        let size = mmap.size();
-
-        // Forget the original mapping because the `MemoryMappingArena` will take care of calling
-        // `munmap` when it is dropped.
-        std::mem::forget(mmap);
-        MemoryMappingArena { addr, size }
+        // All of the arena is covered by the original mapping.
+        let mapping = ArenaMapping {
+            offset: 0,
+            size: mmap.size(),
+            prot: mmap.prot,
+            fd: mmap.fd,
+        };
+        let mut arena = MemoryMappingArena::take_ownership(mmap);
+        arena.mappings.insert(0, mapping);
+        arena
     }
 }
 
@@ -858,8 +1671,10 @@ impl Drop for MemoryMappingArena {
 
 #[cfg(test)]
 mod tests {
+    use super::super::shm::MemfdSeals;
     use super::*;
     use crate::crosvm_mem::{VolatileMemory, VolatileMemoryError};
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::os::unix::io::FromRawFd;
 
     #[test]
@@ -1062,4 +1877,384 @@ mod tests {
             e => panic!("unexpected error: {}", e),
         }
     }
+
+    #[test]
+    fn set_protection() {
+        let ps = pagesize();
+        let mut m = MemoryMapping::new(4 * ps).unwrap();
+        m.write_obj(0x55u8, ps).unwrap();
+        // Safe because the page is only read while it is read-only.
+        unsafe {
+            m.set_protection(ps, ps, Protection::read()).unwrap();
+            assert_eq!(m.read_obj::<u8>(ps).unwrap(), 0x55);
+            m.set_protection(ps, ps, Protection::read_write()).unwrap();
+        }
+        m.write_obj(0xaau8, ps).unwrap();
+
+        // Safe because the protection is left unchanged on failure.
+        match unsafe { m.set_protection(1, ps, Protection::read()) } {
+            Err(Error::NotPageAligned) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match unsafe { m.set_protection(ps, 4 * ps, Protection::read()) } {
+            Err(Error::InvalidAddress) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn arena_set_protection() {
+        let ps = pagesize();
+        let mut m = MemoryMappingArena::new(8 * ps).unwrap();
+        m.add_anon(0, 4 * ps).unwrap();
+        m.set_protection(ps, 2 * ps, Protection::none()).unwrap();
+        m.set_protection(ps, 2 * ps, Protection::read_write())
+            .unwrap();
+        // The anonymous mapping is still in place, rather than the read-only reservation.
+        unsafe {
+            let ptr = m.as_ptr().add(ps);
+            std::ptr::write_volatile(ptr, 0x55u8);
+            assert_eq!(std::ptr::read_volatile(ptr), 0x55);
+        }
+        match m.set_protection(7 * ps, 2 * ps, Protection::read()) {
+            Err(Error::InvalidAddress) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn advise() {
+        let ps = pagesize();
+        let m = MemoryMapping::new_guarded_flags(
+            4 * ps,
+            0,
+            ps,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            None,
+            Protection::read_write(),
+        )
+        .unwrap();
+        m.write_obj(0x55u8, ps).unwrap();
+        m.advise(0, 4 * ps, Advice::WillNeed).unwrap();
+        m.advise(0, 4 * ps, Advice::DoDump).unwrap();
+        assert_eq!(m.read_obj::<u8>(ps).unwrap(), 0x55);
+        // Dropping private anonymous pages zeroes them.
+        m.advise(ps, ps, Advice::DontNeed).unwrap();
+        assert_eq!(m.read_obj::<u8>(ps).unwrap(), 0);
+
+        match m.advise(1, ps, Advice::WillNeed) {
+            Err(Error::NotPageAligned) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match m.advise(ps, 4 * ps, Advice::WillNeed) {
+            Err(Error::InvalidAddress) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    // Returns the permissions of the mapping that contains `addr`, as listed in /proc/self/maps.
+    fn permissions_at(addr: usize) -> Option<String> {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut range = fields.next()?.split('-');
+            let start = usize::from_str_radix(range.next()?, 16).ok()?;
+            let end = usize::from_str_radix(range.next()?, 16).ok()?;
+            if start <= addr && addr < end {
+                fields.next().map(String::from)
+            } else {
+                None
+            }
+        })
+    }
+
+    #[test]
+    fn guard_pages() {
+        let ps = pagesize();
+        let m = MemoryMapping::new_guarded(2 * ps, ps, Protection::read_write()).unwrap();
+        assert_eq!(m.size(), 2 * ps);
+        assert_eq!(m.guard_size(), ps);
+        m.write_obj(0x55u8, 2 * ps - 1).unwrap();
+
+        // The guard pages are reserved, but not accessible.
+        let addr = m.as_ptr() as usize;
+        assert_eq!(permissions_at(addr - ps).unwrap(), "---p");
+        assert_eq!(permissions_at(addr).unwrap(), "rw-s");
+        assert_eq!(permissions_at(addr + 2 * ps).unwrap(), "---p");
+
+        match MemoryMapping::new_guarded(ps, 1, Protection::read_write()) {
+            Err(Error::NotPageAligned) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn resize() {
+        let ps = pagesize();
+        let mut m = MemoryMapping::new_guarded_flags(
+            2 * ps,
+            0,
+            ps,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            None,
+            Protection::read_write(),
+        )
+        .unwrap();
+        m.write_obj(0x55u8, ps).unwrap();
+        m.resize(4 * ps, true).unwrap();
+        assert_eq!(m.size(), 4 * ps);
+        assert_eq!(m.read_obj::<u8>(ps).unwrap(), 0x55);
+        m.write_obj(0xaau8, 4 * ps - 1).unwrap();
+
+        let addr = m.as_ptr();
+        m.resize(ps, false).unwrap();
+        assert_eq!(m.as_ptr(), addr);
+        assert_eq!(m.size(), ps);
+        m.write_obj(0u8, ps).unwrap_err();
+
+        // Shared and file mappings can only be shrunk.
+        let mut m = MemoryMapping::new(2 * ps).unwrap();
+        match m.resize(4 * ps, true) {
+            Err(Error::ResizeBacked) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        m.resize(ps, false).unwrap();
+        let mut shm = SharedMemory::anon().unwrap();
+        shm.set_size(2 * ps as u64).unwrap();
+        let mut m = MemoryMapping::from_fd(&shm, ps).unwrap();
+        match m.resize(2 * ps, true) {
+            Err(Error::ResizeBacked) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(m.size(), ps);
+
+        let mut m = MemoryMapping::new_guarded(ps, ps, Protection::read_write()).unwrap();
+        match m.resize(ps / 2, true) {
+            Err(Error::ResizeGuarded) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn resize_shared_memory() {
+        let ps = pagesize();
+        let mut shm = SharedMemory::anon().unwrap();
+        shm.set_size(ps as u64).unwrap();
+        let mut m = MemoryMapping::from_fd(&shm, ps).unwrap();
+
+        m.resize_shared_memory(&mut shm, 0, 3 * ps, true).unwrap();
+        assert_eq!(shm.size(), 3 * ps as u64);
+        m.write_obj(0x55u8, 3 * ps - 1).unwrap();
+        let other = MemoryMapping::from_fd(&shm, 3 * ps).unwrap();
+        assert_eq!(other.read_obj::<u8>(3 * ps - 1).unwrap(), 0x55);
+
+        m.resize_shared_memory(&mut shm, 0, 2 * ps, false).unwrap();
+        assert_eq!(shm.size(), 2 * ps as u64);
+
+        // A sealed memfd can't be grown, and the mapping is left alone.
+        let mut seals = MemfdSeals::new();
+        seals.set_grow_seal();
+        seals.set_shrink_seal();
+        shm.add_seals(seals).unwrap();
+        match m.resize_shared_memory(&mut shm, 0, 4 * ps, true) {
+            Err(Error::SharedMemoryResize(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(m.size(), 2 * ps);
+        // Shrinking the mapping still works, but the memfd keeps its size.
+        m.resize_shared_memory(&mut shm, 0, ps, false).unwrap();
+        assert_eq!(m.size(), ps);
+        assert_eq!(shm.size(), 2 * ps as u64);
+
+        // Only the shared memory that backs the mapping can be resized with it.
+        match m.resize_shared_memory(&mut shm, ps as u64, 2 * ps, true) {
+            Err(Error::ResizeNotMapped) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut other = SharedMemory::anon().unwrap();
+        match m.resize_shared_memory(&mut other, 0, 2 * ps, true) {
+            Err(Error::ResizeNotMapped) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(m.size(), ps);
+    }
+
+    #[test]
+    fn positioned_file_io() {
+        let mut file = SharedMemory::anon().unwrap();
+        file.write_all(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        file.seek(SeekFrom::Start(1)).unwrap();
+        let m = MemoryMapping::new(16).unwrap();
+
+        m.read_to_memory_at(2, &file, 4, 4).unwrap();
+        assert_eq!(
+            m.read_obj::<u32>(2).unwrap(),
+            u32::from_ne_bytes([5, 6, 7, 8])
+        );
+        match m.read_to_memory_at(0, &file, 6, 4) {
+            Err(Error::ReadToMemory(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        m.write_from_memory_at(2, &file, 8, 4).unwrap();
+        // The file position is left alone.
+        assert_eq!(file.stream_position().unwrap(), 1);
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, [1, 2, 3, 4, 5, 6, 7, 8, 5, 6, 7, 8]);
+
+        match m.write_from_memory_at(0, &file, u64::MAX, 4) {
+            Err(Error::InvalidOffset) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match m.read_to_memory_at(14, &file, 0, 4) {
+            Err(Error::InvalidRange(14, 4, 16)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn arena_mappings() {
+        let ps = pagesize();
+        let shm = SharedMemory::anon().unwrap();
+        let mut m = MemoryMappingArena::new(16 * ps).unwrap();
+        m.add_anon(0, 2 * ps - 1).unwrap();
+        m.add_fd_offset(4 * ps, 8 * ps, &shm, 0x1000).unwrap();
+        assert_eq!(m.mappings().count(), 2);
+        let anon = m.lookup(ps).unwrap();
+        assert_eq!((anon.offset(), anon.size(), anon.fd()), (0, 2 * ps, None));
+        assert!(m.lookup(2 * ps).is_none());
+
+        // Punch a hole in the middle of the second mapping, and change the protection of the
+        // front of what's left.
+        m.remove(6 * ps, 2 * ps).unwrap();
+        m.set_protection(4 * ps, ps, Protection::read()).unwrap();
+        let fd = shm.as_raw_fd();
+        let mappings: Vec<_> = m
+            .mappings()
+            .map(|r| (r.offset(), r.size(), r.protection(), r.fd()))
+            .collect();
+        assert_eq!(
+            mappings,
+            vec![
+                (0, 2 * ps, Protection::read_write(), None),
+                (4 * ps, ps, Protection::read(), Some((fd, 0x1000))),
+                (
+                    5 * ps,
+                    ps,
+                    Protection::read_write(),
+                    Some((fd, 0x1000 + ps as u64))
+                ),
+                (
+                    8 * ps,
+                    4 * ps,
+                    Protection::read_write(),
+                    Some((fd, 0x1000 + 4 * ps as u64))
+                ),
+            ]
+        );
+        assert!(m.lookup(7 * ps).is_none());
+
+        // Replacing part of a mapping splits it too.
+        m.add_anon(10 * ps, 4 * ps).unwrap();
+        assert_eq!(m.lookup(9 * ps).unwrap().size(), 2 * ps);
+        assert_eq!(m.lookup(12 * ps).unwrap().offset(), 10 * ps);
+        assert_eq!(m.lookup(12 * ps).unwrap().fd(), None);
+        assert_eq!(m.mappings().count(), 5);
+    }
+
+    #[test]
+    fn arena_accessors() {
+        let ps = pagesize();
+        let mut shm = SharedMemory::anon().unwrap();
+        shm.set_size(2 * ps as u64).unwrap();
+        let mut m = MemoryMappingArena::new(8 * ps).unwrap();
+        m.add_anon(0, 2 * ps).unwrap();
+        m.add_fd(2 * ps, 2 * ps, &shm).unwrap();
+
+        // Accesses can span adjacent mappings.
+        m.write_obj(0x1122_3344_5566_7788u64, 2 * ps - 4).unwrap();
+        assert_eq!(
+            m.read_obj::<u64>(2 * ps - 4).unwrap(),
+            0x1122_3344_5566_7788
+        );
+        let mut buf = [0u8; 4];
+        shm.seek(SeekFrom::Start(0)).unwrap();
+        shm.read_exact(&mut buf).unwrap();
+        assert_eq!(u32::from_ne_bytes(buf), 0x1122_3344);
+        assert!(m.get_slice(2 * ps - 4, 8).is_ok());
+
+        // Reserved parts of the arena can't be accessed, and nothing is written when part of the
+        // range isn't mapped.
+        let res = m.write_slice(&[1, 2, 3, 4], 4 * ps - 2);
+        assert!(matches!(res, Err(Error::NotMapped(o)) if o == 4 * ps));
+        assert_eq!(m.read_obj::<u8>(4 * ps - 2).unwrap(), 0);
+        let res = m.read_obj::<u8>(5 * ps);
+        assert!(matches!(res, Err(Error::NotMapped(o)) if o == 5 * ps));
+        assert_eq!(
+            m.get_slice(4 * ps - 2, 4).unwrap_err(),
+            VolatileMemoryError::NotMapped { addr: 4 * ps }
+        );
+        let res = m.write_from_memory(3 * ps, &shm, 2 * ps);
+        assert!(matches!(res, Err(Error::NotMapped(o)) if o == 4 * ps));
+
+        // The protection of each mapping is honored.
+        m.set_protection(0, ps, Protection::read()).unwrap();
+        let res = m.write_obj(0u64, ps - 4);
+        assert!(matches!(res, Err(Error::AccessDenied(o)) if o == ps - 4));
+        assert_eq!(m.read_slice(&mut buf, ps - 4).unwrap(), 4);
+        let res = m.read_to_memory(0, &shm, 4);
+        assert!(matches!(res, Err(Error::AccessDenied(0))));
+        assert_eq!(
+            m.get_slice(ps - 4, 8).unwrap_err(),
+            VolatileMemoryError::AccessDenied { addr: ps - 4 }
+        );
+        assert!(m.get_slice(ps, 8).is_ok());
+        shm.seek(SeekFrom::Start(0)).unwrap();
+        m.read_to_memory(ps, &shm, 4).unwrap();
+        assert_eq!(m.read_obj::<u32>(ps).unwrap(), 0x1122_3344);
+    }
+
+    #[test]
+    fn arena_from_mapping() {
+        let ps = pagesize();
+        let mut shm = SharedMemory::anon().unwrap();
+        shm.set_size(4 * ps as u64).unwrap();
+
+        // A converted arena is covered by the original mapping, so it can be accessed right away.
+        let m = MemoryMapping::from_fd_offset(&shm, 2 * ps, ps as u64).unwrap();
+        m.write_obj(0x1122_3344u32, ps - 4).unwrap();
+        let arena = MemoryMappingArena::from(m);
+        let mappings: Vec<_> = arena
+            .mappings()
+            .map(|r| (r.offset(), r.size(), r.protection(), r.fd()))
+            .collect();
+        assert_eq!(
+            mappings,
+            vec![(
+                0,
+                2 * ps,
+                Protection::read_write(),
+                Some((shm.as_raw_fd(), ps as u64))
+            )]
+        );
+        assert_eq!(arena.read_obj::<u32>(ps - 4).unwrap(), 0x1122_3344);
+        arena.write_slice(&[0x55; 8], 2 * ps - 8).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(arena.read_slice(&mut buf, 2 * ps - 8).unwrap(), 8);
+        assert_eq!(buf, [0x55; 8]);
+        arena.get_slice(0, 2 * ps).unwrap().write_bytes(0xaa);
+        assert_eq!(arena.read_obj::<u8>(ps).unwrap(), 0xaa);
+
+        // The protection of the mapping carries over.
+        let mut m = MemoryMapping::new(2 * ps).unwrap();
+        // Safe because the mapping is only read through the arena afterwards.
+        unsafe { m.set_protection(ps, ps, Protection::read()).unwrap() };
+        let arena = MemoryMappingArena::from(m);
+        assert_eq!(arena.lookup(0).unwrap().protection(), Protection::read());
+        assert_eq!(arena.read_obj::<u8>(ps).unwrap(), 0);
+        let res = arena.write_obj(0u8, 0);
+        assert!(matches!(res, Err(Error::AccessDenied(0))));
+    }
 }
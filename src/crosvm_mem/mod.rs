@@ -1,9 +1,16 @@
+pub mod bitmap;
 pub mod data_init;
 pub mod errno;
 pub mod guest_address;
 pub mod guest_memory;
 pub mod mmap;
+pub mod sharing;
 pub mod shm;
+pub mod snapshot;
+pub mod userfaultfd;
+pub mod vhost_user;
+#[cfg(feature = "vm-memory")]
+pub mod vm_memory_adapter;
 pub mod volatile_memory;
 
 pub use data_init::DataInit;
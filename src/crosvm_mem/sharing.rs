@@ -0,0 +1,455 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Sharing a `GuestMemory` with another process over a Unix socket.
+//!
+//! The sender passes the memfd with `SCM_RIGHTS`, along with a `SharingHeader` and one
+//! `SharingRegion` entry for each region. The receiver maps the same ranges of the memfd, so both
+//! processes see the same memory at the same guest addresses. All the integers are stored in the
+//! native byte order, since both ends run on the same host.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::{self, size_of};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::result;
+
+use libc::{self, c_void};
+
+use super::data_init::DataInit;
+use super::guest_address::GuestAddress;
+use super::guest_memory::{self, GuestMemory, RegionBacking};
+use super::mmap::{MappedRegion, Protection};
+use super::shm::{HugePageSize, SharedMemory};
+
+const SHARING_MAGIC: [u8; 8] = *b"CVMSHARE";
+const SHARING_VERSION: u32 = 1;
+// Upper bound for the number of regions, so a corrupted header can't make us allocate an
+// arbitrarily large table.
+const MAX_REGIONS: u64 = 1 << 16;
+/// The maximum number of fds that can be passed along with a message.
+pub(crate) const MAX_FDS: usize = 8;
+// Large enough for a control message with `MAX_FDS` fds.
+const CMSG_BUFFER_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The message doesn't start with the expected magic bytes.
+    InvalidMagic,
+    /// The message was sent with an unsupported version of the format.
+    UnsupportedVersion(u32),
+    /// The huge page size in the message isn't supported.
+    InvalidPageSize(u64),
+    /// The message has more regions than supported.
+    TooManyRegions(u64),
+    /// The message didn't come with the memfd.
+    MissingMemfd,
+    /// The guest memory can't be shared, or can't be rebuilt from the message.
+    GuestMemory(guest_memory::Error),
+    /// Failed to get the size of the received memfd.
+    MemfdSize(super::Error),
+    /// Sending or receiving the message failed.
+    Io(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidMagic => write!(f, "not a shared guest memory message"),
+            UnsupportedVersion(v) => write!(f, "unsupported shared guest memory version {}", v),
+            InvalidPageSize(size) => write!(f, "unsupported huge page size {}", size),
+            TooManyRegions(count) => write!(f, "too many shared guest memory regions: {}", count),
+            MissingMemfd => write!(f, "the memfd is missing"),
+            GuestMemory(e) => write!(f, "failed to share guest memory: {}", e),
+            MemfdSize(e) => write!(f, "failed to get the size of the shared memfd: {}", e),
+            Io(e) => write!(f, "failed to transfer shared guest memory: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<guest_memory::Error> for Error {
+    fn from(e: guest_memory::Error) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SharingHeader {
+    magic: [u8; 8],
+    version: u32,
+    padding: u32,
+    // The size of the huge pages backing the memfd, or 0 for normal pages.
+    huge_page_size: u64,
+    region_count: u64,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for SharingHeader {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SharingRegion {
+    guest_base: u64,
+    size: u64,
+    memfd_offset: u64,
+    // The `PROT_*` flags of the region.
+    prot: u32,
+    padding: u32,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for SharingRegion {}
+
+/// Sends `data` along with `fds` over `socket`, and returns how much of `data` was sent. The fds
+/// are attached to the first byte, and there can't be more than `MAX_FDS` of them.
+pub(crate) fn send_with_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    assert!(fds.len() <= MAX_FDS);
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN];
+    let fds_size = mem::size_of_val(fds) as u32;
+    // Safe because msghdr is a plain C struct, for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut c_void;
+        // Safe because CMSG_SPACE only computes a size.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_size) } as _;
+
+        // Safe because the control buffer is large enough for one control message with `MAX_FDS`
+        // fds.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+            let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+            for (i, &fd) in fds.iter().enumerate() {
+                ptr::write_unaligned(data.add(i), fd);
+            }
+        }
+    }
+
+    loop {
+        // Safe because the message only points to valid buffers, and we check the return value.
+        let ret = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Receives data into `buf` from `socket`, along with the fds that are attached to it. Returns how
+/// much data was received, which is 0 at the end of the stream. Fails with `InvalidData` if more
+/// than `max_fds`, which can't be more than `MAX_FDS`, were attached, after closing all of them.
+pub(crate) fn recv_with_fds(
+    socket: &UnixStream,
+    buf: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(usize, Vec<File>)> {
+    assert!(max_fds <= MAX_FDS);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg_buffer = [0u64; CMSG_BUFFER_LEN];
+    // Safe because msghdr is a plain C struct, for which all zeroes is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut c_void;
+    // Safe because CMSG_SPACE only computes a size.
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE((max_fds * size_of::<RawFd>()) as u32) } as _;
+
+    let count = loop {
+        // Safe because the message only points to valid buffers, and we check the return value.
+        let ret = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+
+    let mut files = Vec::new();
+    // Safe because the kernel initialized the control messages that fit in the buffer, and we
+    // take ownership of every fd that it passed along.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / size_of::<RawFd>() {
+                    files.push(File::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    // The kernel closes the fds that didn't fit in the control buffer, which is rounded up to a
+    // multiple of the word size, so it can still pass along more than `max_fds` of them.
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 || files.len() > max_fds {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many file descriptors",
+        ));
+    }
+    Ok((count, files))
+}
+
+impl GuestMemory {
+    /// Sends this guest memory over `socket`, so that `recv_from_socket` can rebuild an equivalent
+    /// `GuestMemory` in another process.
+    ///
+    /// Only regions that are backed by the shared memfd can be sent. MMIO regions are left out,
+    /// since their handlers are local to this process.
+    pub fn send_over_socket(&self, socket: &UnixStream) -> Result<()> {
+        let mut regions = Vec::new();
+        for region in self.regions() {
+            if !matches!(region.backing(), RegionBacking::SharedMemfd) {
+                return Err(Error::GuestMemory(guest_memory::Error::NotMemfdBacked(
+                    region.start(),
+                )));
+            }
+            regions.push(SharingRegion {
+                guest_base: region.start().offset(),
+                size: region.mapping().size() as u64,
+                memfd_offset: region.memfd_offset(),
+                prot: Into::<libc::c_int>::into(region.protection()) as u32,
+                padding: 0,
+            });
+        }
+
+        let header = SharingHeader {
+            magic: SHARING_MAGIC,
+            version: SHARING_VERSION,
+            padding: 0,
+            huge_page_size: self.huge_page_size().map_or(0, HugePageSize::size),
+            region_count: regions.len() as u64,
+        };
+        let mut message = header.as_slice().to_vec();
+        for region in &regions {
+            message.extend_from_slice(region.as_slice());
+        }
+
+        let sent = send_with_fds(socket, &message, &[self.as_raw_fd()])?;
+        (&*socket).write_all(&message[sent..])?;
+        Ok(())
+    }
+
+    /// Receives a guest memory that was sent with `send_over_socket`, and maps its regions at the
+    /// same guest addresses and with the same protection. The new `GuestMemory` doesn't track dirty
+    /// pages, and its regions don't have guard pages.
+    ///
+    /// Regions that are hotplugged afterwards are allocated past the end of the memfd that each
+    /// side knows about, so only one of the processes should hotplug regions.
+    pub fn recv_from_socket(socket: &UnixStream) -> Result<GuestMemory> {
+        let mut header = SharingHeader::default();
+        let (count, mut files) = recv_with_fds(socket, header.as_mut_slice(), 1)?;
+        if count == 0 {
+            return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        (&*socket).read_exact(&mut header.as_mut_slice()[count..])?;
+        let file = files.pop().ok_or(Error::MissingMemfd)?;
+
+        if header.magic != SHARING_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        if header.version != SHARING_VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        let huge_page_size = match header.huge_page_size {
+            0 => None,
+            size if size == HugePageSize::Size2M.size() => Some(HugePageSize::Size2M),
+            size if size == HugePageSize::Size1G.size() => Some(HugePageSize::Size1G),
+            size => return Err(Error::InvalidPageSize(size)),
+        };
+        if header.region_count > MAX_REGIONS {
+            return Err(Error::TooManyRegions(header.region_count));
+        }
+
+        let mut ranges = Vec::with_capacity(header.region_count as usize);
+        for _ in 0..header.region_count {
+            let region = SharingRegion::from_reader(&mut &*socket)?;
+            // Only the access permissions are meaningful across processes.
+            let prot = region.prot as libc::c_int & (libc::PROT_READ | libc::PROT_WRITE);
+            ranges.push((
+                GuestAddress(region.guest_base),
+                region.size,
+                region.memfd_offset,
+                Protection::from(prot),
+            ));
+        }
+
+        let memfd = SharedMemory::from_file(file).map_err(Error::MemfdSize)?;
+        Ok(GuestMemory::from_memfd(memfd, huge_page_size, &ranges)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::guest_memory::{GuestMemoryBuilder, MemoryRegionOptions};
+    use super::super::pagesize;
+
+    #[test]
+    fn send_recv() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps * 2))
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(ps * 4),
+                ps,
+                &[0xaau8; 4][..],
+            ))
+            .hotplug(true)
+            .build()
+            .unwrap();
+        let gm = gm.insert_region(GuestAddress(ps * 8), ps).unwrap();
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        gm.send_over_socket(&sender).unwrap();
+        let received = GuestMemory::recv_from_socket(&receiver).unwrap();
+
+        assert_eq!(received.num_regions(), 3);
+        assert_eq!(received.memory_size(), gm.memory_size());
+        assert_eq!(received.region_protection(1), Some(Protection::read()));
+        assert_eq!(
+            received.offset_from_base(GuestAddress(ps * 8)).unwrap(),
+            gm.offset_from_base(GuestAddress(ps * 8)).unwrap()
+        );
+        assert_eq!(
+            received
+                .read_obj_from_addr::<u32>(GuestAddress(ps * 4))
+                .unwrap(),
+            0xaaaa_aaaa
+        );
+
+        // Both sides see each other's writes.
+        gm.write_obj_at_addr(0x1122_3344u32, GuestAddress(ps * 2 - 4))
+            .unwrap();
+        assert_eq!(
+            received
+                .read_obj_from_addr::<u32>(GuestAddress(ps * 2 - 4))
+                .unwrap(),
+            0x1122_3344
+        );
+        received
+            .write_obj_at_addr(0x55u8, GuestAddress(ps * 8))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(ps * 8)).unwrap(),
+            0x55
+        );
+        assert!(received
+            .write_obj_at_addr(0u8, GuestAddress(ps * 4))
+            .is_err());
+
+        // The received memory can be hotplugged without clobbering the shared ranges.
+        let received = received.insert_region(GuestAddress(ps * 16), ps).unwrap();
+        received
+            .write_obj_at_addr(0x66u8, GuestAddress(ps * 16))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(ps * 8)).unwrap(),
+            0x55
+        );
+    }
+
+    #[test]
+    fn send_recv_invalid() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), ps).backing(RegionBacking::Anonymous),
+            )
+            .build()
+            .unwrap();
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        match gm.send_over_socket(&sender) {
+            Err(Error::GuestMemory(guest_memory::Error::NotMemfdBacked(GuestAddress(0)))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), ps)]).unwrap();
+        let mut header = SharingHeader {
+            magic: SHARING_MAGIC,
+            version: SHARING_VERSION,
+            padding: 0,
+            huge_page_size: 0,
+            region_count: 1,
+        };
+        let region = SharingRegion {
+            guest_base: 0,
+            size: ps * 2,
+            memfd_offset: 0,
+            prot: libc::PROT_READ as u32,
+            padding: 0,
+        };
+
+        // The region table must come with the memfd.
+        (&sender).write_all(header.as_slice()).unwrap();
+        match GuestMemory::recv_from_socket(&receiver) {
+            Err(Error::MissingMemfd) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // The regions must fit in the memfd.
+        send_with_fds(&sender, header.as_slice(), &[gm.as_raw_fd()]).unwrap();
+        (&sender).write_all(region.as_slice()).unwrap();
+        match GuestMemory::recv_from_socket(&receiver) {
+            Err(Error::GuestMemory(guest_memory::Error::RegionOutsideMemfd { .. })) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        header.magic[0] = 0;
+        send_with_fds(&sender, header.as_slice(), &[gm.as_raw_fd()]).unwrap();
+        match GuestMemory::recv_from_socket(&receiver) {
+            Err(Error::InvalidMagic) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // Only a single fd is expected.
+        header.magic = SHARING_MAGIC;
+        send_with_fds(
+            &sender,
+            header.as_slice(),
+            &[gm.as_raw_fd(), gm.as_raw_fd()],
+        )
+        .unwrap();
+        match GuestMemory::recv_from_socket(&receiver) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+}
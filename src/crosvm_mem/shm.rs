@@ -3,14 +3,19 @@
 // found in the LICENSE file.
 
 use std::ffi::{CStr, CString};
-use std::fs::{read_link, File};
+use std::fs::{read_link, File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use libc::{
-    self, c_char, c_int, c_long, c_uint, close, fcntl, ftruncate64, off64_t, syscall, EINVAL,
-    F_ADD_SEALS, F_GET_SEALS, F_SEAL_GROW, F_SEAL_SEAL, F_SEAL_SHRINK, F_SEAL_WRITE,
-    MFD_ALLOW_SEALING,
+    self, c_char, c_int, c_long, c_uint, close, fallocate64, fcntl, ftruncate64, off64_t, syscall,
+    EINVAL, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, F_ADD_SEALS, F_GET_SEALS, F_SEAL_GROW,
+    F_SEAL_SEAL, F_SEAL_SHRINK, F_SEAL_WRITE, MFD_ALLOW_SEALING,
 };
 
 // use syscall_defines::linux::LinuxSyscall::SYS_memfd_create;
@@ -24,10 +29,172 @@ use super::{errno, errno_result, Result};
 pub struct SharedMemory {
     fd: File,
     size: u64,
+    backing: SharedMemoryBacking,
+}
+
+/// The kind of file that backs a `SharedMemory`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SharedMemoryBacking {
+    /// A memfd, created with `memfd_create`.
+    Memfd,
+    /// An unnamed temporary file in a tmpfs or hugetlbfs directory, created with `O_TMPFILE`
+    /// because `memfd_create` wasn't available.
+    TmpFile,
+    /// A POSIX shared memory object that was unlinked right after it was created with `shm_open`,
+    /// because neither `memfd_create` nor `O_TMPFILE` were available.
+    PosixShm,
+    /// The file that was passed to `SharedMemory::from_file`.
+    File,
 }
 
 // from <sys/memfd.h>
 const MFD_CLOEXEC: c_uint = 0x0001;
+const MFD_HUGETLB: c_uint = 0x0004;
+const MFD_HUGE_SHIFT: c_uint = 26;
+const MFD_NOEXEC_SEAL: c_uint = 0x0008;
+const MFD_EXEC: c_uint = 0x0010;
+
+// from <linux/magic.h>
+const HUGETLBFS_MAGIC: u32 = 0x9584_58f6;
+
+// The directory for `O_TMPFILE` files when `memfd_create` isn't available, unless another one is
+// configured with `MemfdOptions::tmpfile_dir`.
+const DEFAULT_TMPFILE_DIR: &str = "/dev/shm";
+
+// Makes the names of the POSIX shared memory objects that this process creates unique.
+static POSIX_SHM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// from <linux/fcntl.h>
+const F_SEAL_FUTURE_WRITE: c_int = 0x0010;
+const F_SEAL_EXEC: c_int = 0x0020;
+
+/// The size of the huge pages backing a hugetlb memfd.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// 2 MiB huge pages.
+    Size2M,
+    /// 1 GiB huge pages.
+    Size1G,
+}
+
+impl HugePageSize {
+    /// Returns the huge page size in bytes.
+    pub fn size(self) -> u64 {
+        1 << self.shift()
+    }
+
+    fn shift(self) -> c_uint {
+        match self {
+            HugePageSize::Size2M => 21,
+            HugePageSize::Size1G => 30,
+        }
+    }
+
+    // The `memfd_create` flags that select this huge page size.
+    fn memfd_flags(self) -> c_uint {
+        MFD_HUGETLB | (self.shift() << MFD_HUGE_SHIFT)
+    }
+}
+
+/// Whether the contents of a memfd can be executed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemfdExec {
+    /// Leaves the choice to the kernel, which depends on the `vm.memfd_noexec` sysctl on the
+    /// kernels that support it.
+    Default,
+    /// The memfd can't be executed, which is sealed with the exec seal (`MFD_NOEXEC_SEAL`). This
+    /// implies that sealing is allowed.
+    NoExecSeal,
+    /// The memfd can be executed (`MFD_EXEC`).
+    Exec,
+}
+
+/// Options for creating a `SharedMemory` with `SharedMemory::with_options`.
+///
+/// The defaults match `SharedMemory::new`: unnamed, backed by normal pages, with sealing allowed,
+/// and with the kernel's default executability.
+///
+/// If `memfd_create` isn't available, because the kernel is too old or a seccomp policy blocks it,
+/// the shared memory falls back to an `O_TMPFILE` file in a tmpfs or hugetlbfs directory, and then
+/// to a POSIX shared memory object if it's backed by normal pages. Seals can't be added to either
+/// of those, and neither can be named. There is no fallback for memfds with an explicit
+/// `MemfdExec`, since it couldn't be honored.
+#[derive(Clone, Debug)]
+pub struct MemfdOptions {
+    name: Option<Vec<u8>>,
+    huge_page_size: Option<HugePageSize>,
+    allow_sealing: bool,
+    exec: MemfdExec,
+    tmpfile_dir: Option<PathBuf>,
+}
+
+impl MemfdOptions {
+    /// Returns the default options.
+    pub fn new() -> MemfdOptions {
+        MemfdOptions {
+            name: None,
+            huge_page_size: None,
+            allow_sealing: true,
+            exec: MemfdExec::Default,
+            tmpfile_dir: None,
+        }
+    }
+
+    /// Sets the name that appears in `/proc/self/fd/<shm fd>`. It may not contain NUL characters.
+    pub fn name<T: Into<Vec<u8>>>(mut self, name: T) -> MemfdOptions {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Backs the memfd with huge pages of `page_size`, as for `SharedMemory::new_hugetlb`.
+    pub fn huge_page_size(mut self, page_size: HugePageSize) -> MemfdOptions {
+        self.huge_page_size = Some(page_size);
+        self
+    }
+
+    /// Sets whether seals can be added to the memfd.
+    pub fn allow_sealing(mut self, allow_sealing: bool) -> MemfdOptions {
+        self.allow_sealing = allow_sealing;
+        self
+    }
+
+    /// Sets whether the memfd can be executed. The choices other than `MemfdExec::Default` need
+    /// Linux 6.3 or later, see `kernel_has_memfd_exec`.
+    pub fn exec(mut self, exec: MemfdExec) -> MemfdOptions {
+        self.exec = exec;
+        self
+    }
+
+    /// Sets the directory that the `O_TMPFILE` fallback creates its file in. It defaults to
+    /// `/dev/shm` for memfds backed by normal pages. Memfds backed by huge pages only fall back if a
+    /// directory is set, which has to be a hugetlbfs mount with the same page size.
+    pub fn tmpfile_dir<P: Into<PathBuf>>(mut self, dir: P) -> MemfdOptions {
+        self.tmpfile_dir = Some(dir.into());
+        self
+    }
+
+    fn memfd_flags(&self) -> c_uint {
+        let mut flags = 0;
+        if let Some(page_size) = self.huge_page_size {
+            flags |= page_size.memfd_flags();
+        }
+        if self.allow_sealing {
+            flags |= MFD_ALLOW_SEALING;
+        }
+        flags
+            | match self.exec {
+                MemfdExec::Default => 0,
+                MemfdExec::NoExecSeal => MFD_NOEXEC_SEAL,
+                MemfdExec::Exec => MFD_EXEC,
+            }
+    }
+}
+
+impl Default for MemfdOptions {
+    fn default() -> MemfdOptions {
+        MemfdOptions::new()
+    }
+}
 
 unsafe fn memfd_create(name: *const c_char, flags: c_uint) -> c_int {
     syscall(SYS_memfd_create as c_long, name, flags) as c_int
@@ -99,6 +266,32 @@ impl MemfdSeals {
     pub fn set_seal_seal(&mut self) {
         self.0 |= F_SEAL_SEAL;
     }
+
+    /// True of the future write seal bit is present.
+    #[inline]
+    pub fn future_write_seal(self) -> bool {
+        self.0 & F_SEAL_FUTURE_WRITE != 0
+    }
+
+    /// Sets the future write seal bit, which prevents new writable mappings and writes through the
+    /// file descriptor, while the existing writable mappings keep working. It needs Linux 5.1 or
+    /// later, see `kernel_has_memfd_future_write_seal`.
+    #[inline]
+    pub fn set_future_write_seal(&mut self) {
+        self.0 |= F_SEAL_FUTURE_WRITE;
+    }
+
+    /// True of the exec seal bit is present.
+    #[inline]
+    pub fn exec_seal(self) -> bool {
+        self.0 & F_SEAL_EXEC != 0
+    }
+
+    /// Sets the exec seal bit, which prevents changing the executable permissions of the memfd.
+    #[inline]
+    pub fn set_exec_seal(&mut self) {
+        self.0 |= F_SEAL_EXEC;
+    }
 }
 
 impl SharedMemory {
@@ -113,6 +306,18 @@ impl SharedMemory {
         ))
     }
 
+    /// Convenience function for `SharedMemory::new_hugetlb` that is always named and accepts a
+    /// wide variety of string-like types.
+    pub fn named_hugetlb<T: Into<Vec<u8>>>(
+        name: T,
+        page_size: HugePageSize,
+    ) -> Result<SharedMemory> {
+        Self::new_hugetlb(
+            Some(&CString::new(name).map_err(|_| errno::Error::new(EINVAL))?),
+            page_size,
+        )
+    }
+
     /// Convenience function for `SharedMemory::new` that has an arbitrary and unspecified name.
     pub fn anon() -> Result<SharedMemory> {
         Self::new(None)
@@ -123,21 +328,148 @@ impl SharedMemory {
     /// If a name is given, it will appear in `/proc/self/fd/<shm fd>` for the purposes of
     /// debugging. The name does not need to be unique.
     ///
-    /// The file descriptor is opened with the close on exec flag and allows memfd sealing.
+    /// The file descriptor is opened with the close on exec flag. It allows memfd sealing if it is
+    /// backed by a memfd, but it falls back to an unsealable tmpfile or POSIX shared memory object
+    /// when `memfd_create` isn't available; see `backing` and `sealing_supported`.
     pub fn new(name: Option<&CStr>) -> Result<SharedMemory> {
+        Self::with_options(&MemfdOptions {
+            name: name.map(|n| n.to_bytes().to_vec()),
+            ..MemfdOptions::new()
+        })
+    }
+
+    /// Creates a new shared memory file descriptor with zero size, backed by huge pages of the
+    /// given size.
+    ///
+    /// The size of the shared memory must be a multiple of the huge page size. Mapping it fails if
+    /// the host doesn't have enough huge pages of that size reserved.
+    pub fn new_hugetlb(name: Option<&CStr>, page_size: HugePageSize) -> Result<SharedMemory> {
+        Self::with_options(&MemfdOptions {
+            name: name.map(|n| n.to_bytes().to_vec()),
+            ..MemfdOptions::new().huge_page_size(page_size)
+        })
+    }
+
+    /// Creates a new shared memory file descriptor with zero size, configured by `options`.
+    ///
+    /// The file descriptor is always opened with the close on exec flag. Creation fails with
+    /// `EINVAL` if the name contains NUL characters, or if the kernel doesn't support one of the
+    /// options. See `MemfdOptions` for the fallbacks when `memfd_create` isn't available.
+    pub fn with_options(options: &MemfdOptions) -> Result<SharedMemory> {
+        let name = match &options.name {
+            Some(name) => Some(CString::new(name.clone()).map_err(|_| errno::Error::new(EINVAL))?),
+            None => None,
+        };
+        let err = match Self::create(name.as_deref(), options.memfd_flags()) {
+            Ok(shm) => return Ok(shm),
+            Err(e) => e,
+        };
+        // Seccomp policies usually fail the calls they filter with one of these.
+        let unavailable = matches!(err.errno(), libc::ENOSYS | libc::EPERM | libc::EACCES);
+        if !unavailable || options.exec != MemfdExec::Default {
+            return Err(err);
+        }
+
+        let tmpfile_dir = match (&options.tmpfile_dir, options.huge_page_size) {
+            (Some(dir), _) => Some(dir.as_path()),
+            (None, None) => Some(Path::new(DEFAULT_TMPFILE_DIR)),
+            (None, Some(_)) => None,
+        };
+        let tmpfile = tmpfile_dir.map(|dir| Self::create_tmpfile(dir, options.huge_page_size));
+        match (tmpfile, options.huge_page_size) {
+            (Some(Ok(shm)), _) => Ok(shm),
+            (Some(Err(e)), Some(_)) => Err(e),
+            (None, Some(_)) => Err(err),
+            (_, None) => Self::create_posix_shm(),
+        }
+    }
+
+    fn create(name: Option<&CStr>, flags: c_uint) -> Result<SharedMemory> {
         let shm_name = name
             .map(|n| n.as_ptr())
             .unwrap_or(b"/crosvm_shm\0".as_ptr() as *const c_char);
         // The following are safe because we give a valid C string and check the
         // results of the memfd_create call.
-        let fd = unsafe { memfd_create(shm_name, MFD_CLOEXEC | MFD_ALLOW_SEALING) };
+        let fd = unsafe { memfd_create(shm_name, MFD_CLOEXEC | flags) };
         if fd < 0 {
             return errno_result();
         }
 
         let file = unsafe { File::from_raw_fd(fd) };
 
-        Ok(SharedMemory { fd: file, size: 0 })
+        Ok(SharedMemory {
+            fd: file,
+            size: 0,
+            backing: SharedMemoryBacking::Memfd,
+        })
+    }
+
+    // Creates an unnamed file in `dir`, which must be a hugetlbfs mount with the given page size if
+    // `huge_page_size` is set.
+    fn create_tmpfile(dir: &Path, huge_page_size: Option<HugePageSize>) -> Result<SharedMemory> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(dir)?;
+
+        if let Some(page_size) = huge_page_size {
+            let mut stat = MaybeUninit::<libc::statfs>::uninit();
+            // Safe because the kernel only writes to the struct, and we check the return value.
+            let ret = unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) };
+            if ret < 0 {
+                return errno_result();
+            }
+            // Safe because fstatfs succeeded, so the struct is initialized.
+            let stat = unsafe { stat.assume_init() };
+            if stat.f_type as u32 != HUGETLBFS_MAGIC || stat.f_bsize as u64 != page_size.size() {
+                return Err(errno::Error::new(EINVAL));
+            }
+        }
+
+        Ok(SharedMemory {
+            fd: file,
+            size: 0,
+            backing: SharedMemoryBacking::TmpFile,
+        })
+    }
+
+    // Creates a POSIX shared memory object, and unlinks it so it's only reachable through the fd.
+    fn create_posix_shm() -> Result<SharedMemory> {
+        loop {
+            let name = CString::new(format!(
+                "/crosvm_shm.{}.{}",
+                process::id(),
+                POSIX_SHM_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ))
+            .unwrap();
+            // Safe because we give a valid C string and check the result.
+            let fd = unsafe {
+                libc::shm_open(
+                    name.as_ptr(),
+                    libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                    0o600,
+                )
+            };
+            if fd < 0 {
+                // The name is left over from an earlier process with the same pid.
+                if errno::Error::last().errno() == libc::EEXIST {
+                    continue;
+                }
+                return errno_result();
+            }
+            // Safe because we own the new fd.
+            let file = unsafe { File::from_raw_fd(fd) };
+            // Safe because we give a valid C string. The object stays alive while it's open.
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+
+            return Ok(SharedMemory {
+                fd: file,
+                size: 0,
+                backing: SharedMemoryBacking::PosixShm,
+            });
+        }
     }
 
     /// Constructs a `SharedMemory` instance from a `File` that represents shared memory.
@@ -149,9 +481,34 @@ impl SharedMemory {
         Ok(SharedMemory {
             fd: file,
             size: file_size as u64,
+            backing: SharedMemoryBacking::File,
         })
     }
 
+    /// Creates a new `SharedMemory` instance that refers to the same shared memory as this one,
+    /// through a duplicated file descriptor.
+    ///
+    /// Both instances start out with the same size, but changes made through `set_size` are only
+    /// reflected in `size` for the instance they were made through.
+    pub fn try_clone(&self) -> Result<SharedMemory> {
+        Ok(SharedMemory {
+            fd: self.fd.try_clone()?,
+            size: self.size,
+            backing: self.backing,
+        })
+    }
+
+    /// Returns the kind of file that backs the shared memory.
+    pub fn backing(&self) -> SharedMemoryBacking {
+        self.backing
+    }
+
+    /// Returns true if seals can be added to the shared memory. That's only the case for memfds
+    /// that were created with sealing allowed, and that weren't sealed with the seal seal yet.
+    pub fn sealing_supported(&self) -> bool {
+        matches!(self.get_seals(), Ok(seals) if !seals.seal_seal())
+    }
+
     /// Gets the memfd seals that have already been added to this.
     ///
     /// This may fail if this instance was not constructed from a memfd.
@@ -196,6 +553,23 @@ impl SharedMemory {
         Ok(())
     }
 
+    /// Releases the pages of `len` bytes of the shared memory starting at `offset`, which read as
+    /// zeroes afterwards. The size of the shared memory stays the same.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+        let ret = unsafe {
+            fallocate64(
+                self.fd.as_raw_fd(),
+                FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                offset as off64_t,
+                len as off64_t,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
     /// Reads the name from the underlying file as a `String`.
     ///
     /// If the underlying file was not created with `SharedMemory::new` or with `memfd_create`, the
@@ -294,12 +668,41 @@ pub fn kernel_has_memfd() -> bool {
     true
 }
 
+/// Checks if the kernel supports `MemfdSeals::set_future_write_seal`. It was introduced in 5.1.
+pub fn kernel_has_memfd_future_write_seal() -> bool {
+    let mut shm = match SharedMemory::anon() {
+        Ok(shm) => shm,
+        Err(_) => return false,
+    };
+    let mut seals = MemfdSeals::new();
+    seals.set_future_write_seal();
+    shm.add_seals(seals).is_ok()
+}
+
+/// Checks if the kernel supports the choices of `MemfdExec` other than the default. They were
+/// introduced in 6.3.
+pub fn kernel_has_memfd_exec() -> bool {
+    // Safe because we give a valid C string, and close the fd if the call succeeds.
+    unsafe {
+        let fd = memfd_create(
+            b"/test_memfd_exec\0".as_ptr() as *const c_char,
+            MFD_CLOEXEC | MFD_NOEXEC_SEAL,
+        );
+        if fd < 0 {
+            return false;
+        }
+        close(fd);
+    }
+    true
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::ffi::CString;
 
+    use crate::crosvm_mem::mmap::Protection;
     use crate::crosvm_mem::MemoryMapping;
     use crate::crosvm_mem::VolatileMemory;
 
@@ -444,4 +847,118 @@ mod tests {
             assert_eq!(mmap2.get_ref::<u8>(i).unwrap().load(), 0x45u8);
         }
     }
+
+    #[test]
+    fn with_options() {
+        if !kernel_has_memfd() {
+            return;
+        }
+        let shm = SharedMemory::with_options(&MemfdOptions::new().name("options"))
+            .expect("failed to create shared memory");
+        assert_eq!(shm.read_name(), Ok("options".to_owned()));
+        assert_eq!(shm.get_seals().unwrap().bitmask(), 0);
+
+        let mut shm = SharedMemory::with_options(&MemfdOptions::new().allow_sealing(false))
+            .expect("failed to create shared memory");
+        // Memfds that don't allow sealing come with the seal seal.
+        assert!(shm.get_seals().unwrap().seal_seal());
+        let mut seals = MemfdSeals::new();
+        seals.set_grow_seal();
+        shm.add_seals(seals).unwrap_err();
+
+        assert!(SharedMemory::with_options(&MemfdOptions::new().name("bad\0name")).is_err());
+
+        if kernel_has_memfd_exec() {
+            let shm = SharedMemory::with_options(&MemfdOptions::new().exec(MemfdExec::NoExecSeal))
+                .expect("failed to create shared memory");
+            assert!(shm.get_seals().unwrap().exec_seal());
+        }
+    }
+
+    #[test]
+    fn future_write_seal() {
+        if !kernel_has_memfd() || !kernel_has_memfd_future_write_seal() {
+            return;
+        }
+        let mut shm = SharedMemory::anon().expect("failed to create shared memory");
+        shm.set_size(4096)
+            .expect("failed to set shared memory size");
+        let writable = MemoryMapping::from_fd(&shm, 4096).expect("failed to map shared memory");
+
+        let mut seals = MemfdSeals::new();
+        seals.set_future_write_seal();
+        shm.add_seals(seals).expect("failed to add seals");
+        assert!(shm.get_seals().unwrap().future_write_seal());
+
+        // The existing mapping can still be written, but no new writable one can be created.
+        writable.write_obj(0x45u8, 0).unwrap();
+        MemoryMapping::from_fd(&shm, 4096).unwrap_err();
+        let read_only = MemoryMapping::from_fd_offset_protection(&shm, 4096, 0, Protection::read())
+            .expect("failed to map shared memory");
+        assert_eq!(read_only.read_obj::<u8>(0).unwrap(), 0x45);
+        shm.write_all(&[0]).unwrap_err();
+    }
+
+    // Checks that `shm` works as the backing of mappings.
+    fn check_mappable(shm: &mut SharedMemory) {
+        shm.set_size(8192)
+            .expect("failed to set shared memory size");
+        let mmap1 = MemoryMapping::from_fd(shm, 8192).expect("failed to map shared memory");
+        let mmap2 =
+            MemoryMapping::from_fd_offset(&*shm, 4096, 4096).expect("failed to map shared memory");
+        mmap1.write_obj(0x45u8, 4096).unwrap();
+        assert_eq!(mmap2.read_obj::<u8>(0).unwrap(), 0x45);
+    }
+
+    #[test]
+    fn sealing_supported() {
+        if !kernel_has_memfd() {
+            return;
+        }
+        let mut shm = SharedMemory::anon().expect("failed to create shared memory");
+        assert_eq!(shm.backing(), SharedMemoryBacking::Memfd);
+        assert!(shm.sealing_supported());
+        let mut seals = MemfdSeals::new();
+        seals.set_seal_seal();
+        shm.add_seals(seals).expect("failed to add seals");
+        assert!(!shm.sealing_supported());
+    }
+
+    #[test]
+    fn tmpfile_fallback() {
+        let mut shm = match SharedMemory::create_tmpfile(Path::new(DEFAULT_TMPFILE_DIR), None) {
+            Ok(shm) => shm,
+            // There's no tmpfs mounted at /dev/shm.
+            Err(_) => return,
+        };
+        assert_eq!(shm.backing(), SharedMemoryBacking::TmpFile);
+        assert!(!shm.sealing_supported());
+        check_mappable(&mut shm);
+
+        // Only hugetlbfs directories can back huge pages.
+        match SharedMemory::create_tmpfile(
+            Path::new(DEFAULT_TMPFILE_DIR),
+            Some(HugePageSize::Size2M),
+        ) {
+            Err(e) => assert_eq!(e.errno(), EINVAL),
+            Ok(_) => panic!("created a hugetlb tmpfile on tmpfs"),
+        }
+    }
+
+    #[test]
+    fn posix_shm_fallback() {
+        let mut shm = match SharedMemory::create_posix_shm() {
+            Ok(shm) => shm,
+            // There's no tmpfs mounted at /dev/shm.
+            Err(_) => return,
+        };
+        assert_eq!(shm.backing(), SharedMemoryBacking::PosixShm);
+        assert!(!shm.sealing_supported());
+        check_mappable(&mut shm);
+        let mut clone = shm.try_clone().unwrap();
+        assert_eq!(clone.backing(), SharedMemoryBacking::PosixShm);
+        let mut seals = MemfdSeals::new();
+        seals.set_shrink_seal();
+        clone.add_seals(seals).unwrap_err();
+    }
 }
@@ -0,0 +1,407 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Saving the contents of a `GuestMemory` to a file, and restoring them.
+//!
+//! A snapshot consists of a `SnapshotHeader`, followed by one `SnapshotRegion` entry for each
+//! region, followed by the contents of each region in the same order. The contents are stored
+//! sparsely: each page that isn't entirely zero is stored as its index within the region followed
+//! by the page data, and the list of pages of a region is terminated by `END_OF_REGION`. All the
+//! integers are stored in the native byte order.
+//!
+//! Only memory whose regions are all backed by the shared memfd can be saved. Their protection
+//! and the huge page size of the memfd are restored along with their contents.
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::result;
+
+use super::data_init::DataInit;
+use super::guest_address::GuestAddress;
+use super::guest_memory::{
+    self, GuestMemory, GuestMemoryBuilder, MemoryRegionOptions, RegionBacking,
+};
+use super::mmap::{MappedRegion, MemoryMapping, Protection};
+use super::pagesize;
+use super::shm::HugePageSize;
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"CVMSNAP\0";
+const SNAPSHOT_VERSION: u32 = 2;
+// Page indices are stored as `u64`s, and a region can't have this many pages.
+const END_OF_REGION: u64 = u64::MAX;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The snapshot doesn't start with the expected magic bytes.
+    InvalidMagic,
+    /// The snapshot was saved with an unsupported version of the format.
+    UnsupportedVersion(u32),
+    /// The page size recorded in the snapshot isn't the page size of the host.
+    InvalidPageSize(u32),
+    /// The huge page size recorded in the snapshot isn't supported.
+    InvalidHugePageSize(u64),
+    /// A page index recorded in the snapshot lies outside of its region.
+    InvalidPage { region: usize, page: u64 },
+    /// Accessing guest memory failed.
+    GuestMemory(guest_memory::Error),
+    /// Reading or writing the snapshot failed.
+    Io(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidMagic => write!(f, "not a guest memory snapshot"),
+            UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            InvalidPageSize(size) => write!(f, "invalid snapshot page size {}", size),
+            InvalidHugePageSize(size) => write!(f, "invalid snapshot huge page size {}", size),
+            InvalidPage { region, page } => write!(
+                f,
+                "page {} is out of bounds of snapshot region {}",
+                page, region
+            ),
+            GuestMemory(e) => write!(f, "failed to access guest memory: {}", e),
+            Io(e) => write!(f, "failed to access snapshot: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<guest_memory::Error> for Error {
+    fn from(e: guest_memory::Error) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SnapshotHeader {
+    magic: [u8; 8],
+    version: u32,
+    page_size: u32,
+    region_count: u64,
+    // 0 if the memfd isn't backed by huge pages.
+    huge_page_size: u64,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for SnapshotHeader {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SnapshotRegion {
+    guest_base: u64,
+    size: u64,
+    prot: u32,
+    padding: u32,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for SnapshotRegion {}
+
+impl GuestMemory {
+    /// Writes a snapshot of the layout and contents of this guest memory to `w`.
+    ///
+    /// Pages that only contain zeroes are skipped. The guest should not be running while the
+    /// snapshot is taken, or the result might not be consistent. Fails with `NotMemfdBacked` if a
+    /// region isn't backed by the shared memfd. MMIO regions are left out, since their handlers
+    /// are local to this process.
+    pub fn save_snapshot<W: Write>(&self, w: &mut W) -> Result<()> {
+        let page_size = pagesize();
+        let mut regions = Vec::new();
+        for region in self.regions() {
+            if !matches!(region.backing(), RegionBacking::SharedMemfd) {
+                return Err(Error::GuestMemory(guest_memory::Error::NotMemfdBacked(
+                    region.start(),
+                )));
+            }
+            regions.push(SnapshotRegion {
+                guest_base: region.start().offset(),
+                size: region.mapping().size() as u64,
+                prot: Into::<libc::c_int>::into(region.protection()) as u32,
+                padding: 0,
+            });
+        }
+
+        let header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            version: SNAPSHOT_VERSION,
+            page_size: page_size as u32,
+            region_count: regions.len() as u64,
+            huge_page_size: self.huge_page_size().map_or(0, HugePageSize::size),
+        };
+        w.write_all(header.as_slice())?;
+        for region in &regions {
+            w.write_all(region.as_slice())?;
+        }
+
+        let mut page = vec![0u8; page_size];
+        for region in &regions {
+            // Region sizes are always a multiple of the page size.
+            for index in 0..region.size / page_size as u64 {
+                let addr = GuestAddress(region.guest_base + index * page_size as u64);
+                self.read_exact_at_addr(&mut page, addr)?;
+                if page.iter().all(|&b| b == 0) {
+                    continue;
+                }
+                w.write_all(index.as_slice())?;
+                w.write_all(&page)?;
+            }
+            w.write_all(END_OF_REGION.as_slice())?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new `GuestMemory` from a snapshot written by `save_snapshot`.
+    ///
+    /// The regions are recreated at the same guest addresses, with the same sizes and protection,
+    /// and in a memfd with the same huge page size. They are laid out back to back in the new
+    /// memfd, so their offsets in it are not preserved if the original memory had holes in its
+    /// memfd, e.g. because of hot-unplug. Other options, such as dirty tracking, are left at the
+    /// defaults of `GuestMemoryBuilder`.
+    pub fn restore_snapshot<R: Read>(r: &mut R) -> Result<GuestMemory> {
+        let header = SnapshotHeader::from_reader(&mut *r)?;
+        if header.magic != SNAPSHOT_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        if header.version != SNAPSHOT_VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        // The pages are restored in units of the host page size, which also keeps a corrupted
+        // header from making us allocate an arbitrarily large buffer.
+        if header.page_size as usize != pagesize() {
+            return Err(Error::InvalidPageSize(header.page_size));
+        }
+        let page_size = header.page_size as u64;
+
+        let mut builder = GuestMemoryBuilder::new();
+        match header.huge_page_size {
+            0 => {}
+            size if size == HugePageSize::Size2M.size() => {
+                builder = builder.huge_page_size(HugePageSize::Size2M)
+            }
+            size if size == HugePageSize::Size1G.size() => {
+                builder = builder.huge_page_size(HugePageSize::Size1G)
+            }
+            size => return Err(Error::InvalidHugePageSize(size)),
+        }
+        let mut regions = Vec::new();
+        for _ in 0..header.region_count {
+            let region = SnapshotRegion::from_reader(&mut *r)?;
+            // Only the access permissions are meaningful across processes.
+            let prot = region.prot as libc::c_int & (libc::PROT_READ | libc::PROT_WRITE);
+            builder = builder.region(
+                MemoryRegionOptions::new(GuestAddress(region.guest_base), region.size)
+                    .protection(Protection::from(prot)),
+            );
+            regions.push(region);
+        }
+        let mem = builder.build()?;
+
+        let mut page = vec![0u8; page_size as usize];
+        for (index, region) in regions.iter().enumerate() {
+            // The pages are written through a separate mapping of the memfd, since the region
+            // itself may be read-only.
+            let guest_base = GuestAddress(region.guest_base);
+            let mapping = MemoryMapping::from_fd_offset(
+                mem.as_ref(),
+                region.size as usize,
+                mem.offset_from_base(guest_base)?,
+            )
+            .map_err(guest_memory::Error::MemoryMappingFailed)?;
+            loop {
+                let page_index = u64::from_reader(&mut *r)?;
+                if page_index == END_OF_REGION {
+                    break;
+                }
+                let offset = page_index
+                    .checked_mul(page_size)
+                    .filter(|offset| {
+                        matches!(offset.checked_add(page_size), Some(end) if end <= region.size)
+                    })
+                    .ok_or(Error::InvalidPage {
+                        region: index,
+                        page: page_index,
+                    })?;
+                r.read_exact(&mut page)?;
+                mapping.write_slice(&page, offset as usize).map_err(|e| {
+                    guest_memory::Error::MemoryAccess(guest_base.unchecked_add(offset), e)
+                })?;
+            }
+        }
+
+        Ok(mem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn save_restore() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), ps * 4), (GuestAddress(ps * 16), ps * 2)])
+            .unwrap();
+        gm.write_obj_at_addr(0x1122_3344u32, GuestAddress(ps + 8))
+            .unwrap();
+        gm.write_obj_at_addr(0x5566_7788u32, GuestAddress(ps * 18 - 4))
+            .unwrap();
+
+        let mut snapshot = Vec::new();
+        gm.save_snapshot(&mut snapshot).unwrap();
+
+        // Only the two non-zero pages are stored.
+        let table_size =
+            std::mem::size_of::<SnapshotHeader>() + 2 * std::mem::size_of::<SnapshotRegion>();
+        assert_eq!(snapshot.len(), table_size + 2 * (8 + ps as usize) + 2 * 8);
+
+        let restored = GuestMemory::restore_snapshot(&mut Cursor::new(&snapshot)).unwrap();
+        assert_eq!(restored.num_regions(), 2);
+        assert_eq!(restored.memory_size(), ps * 6);
+        assert_eq!(restored.end_addr(), GuestAddress(ps * 18));
+        assert_eq!(
+            restored
+                .read_obj_from_addr::<u32>(GuestAddress(ps + 8))
+                .unwrap(),
+            0x1122_3344
+        );
+        assert_eq!(
+            restored
+                .read_obj_from_addr::<u32>(GuestAddress(ps * 18 - 4))
+                .unwrap(),
+            0x5566_7788
+        );
+        assert_eq!(
+            restored
+                .read_obj_from_addr::<u64>(GuestAddress(ps * 2))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn save_restore_layout() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(0x0),
+                ps,
+                &[0xaau8; 4][..],
+            ))
+            .region(MemoryRegionOptions::new(GuestAddress(ps * 4), ps))
+            .build()
+            .unwrap();
+        let mut snapshot = Vec::new();
+        gm.save_snapshot(&mut snapshot).unwrap();
+
+        // The read-only region keeps both its contents and its protection.
+        let restored = GuestMemory::restore_snapshot(&mut Cursor::new(&snapshot)).unwrap();
+        assert_eq!(restored.region_protection(0), Some(Protection::read()));
+        assert_eq!(
+            restored.region_protection(1),
+            Some(Protection::read_write())
+        );
+        assert_eq!(
+            restored.read_obj_from_addr::<u32>(GuestAddress(0)).unwrap(),
+            0xaaaa_aaaa
+        );
+        assert!(restored.write_obj_at_addr(0u8, GuestAddress(0)).is_err());
+        assert_eq!(restored.huge_page_size(), None);
+
+        // Regions that aren't part of the memfd can't be restored.
+        let gm = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), ps).backing(RegionBacking::Anonymous),
+            )
+            .build()
+            .unwrap();
+        match gm.save_snapshot(&mut Vec::new()) {
+            Err(Error::GuestMemory(guest_memory::Error::NotMemfdBacked(GuestAddress(0)))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn restore_invalid() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), ps)]).unwrap();
+        gm.write_obj_at_addr(1u8, GuestAddress(0)).unwrap();
+        let mut snapshot = Vec::new();
+        gm.save_snapshot(&mut snapshot).unwrap();
+
+        let mut bad_magic = snapshot.clone();
+        bad_magic[0] = 0;
+        match GuestMemory::restore_snapshot(&mut Cursor::new(&bad_magic)) {
+            Err(Error::InvalidMagic) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // Point the only page past the end of its region.
+        let mut bad_page = snapshot.clone();
+        let page_offset =
+            std::mem::size_of::<SnapshotHeader>() + std::mem::size_of::<SnapshotRegion>();
+        bad_page[page_offset..page_offset + 8].copy_from_slice(1u64.as_slice());
+        match GuestMemory::restore_snapshot(&mut Cursor::new(&bad_page)) {
+            Err(Error::InvalidPage { region: 0, page: 1 }) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // A page index whose end doesn't fit in a u64.
+        let huge_page = u64::MAX / ps;
+        bad_page[page_offset..page_offset + 8].copy_from_slice(huge_page.as_slice());
+        match GuestMemory::restore_snapshot(&mut Cursor::new(&bad_page)) {
+            Err(Error::InvalidPage { region: 0, page }) => assert_eq!(page, huge_page),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // Snapshots taken with another page size can't be restored.
+        let mut bad_page_size = snapshot.clone();
+        bad_page_size[12..16].copy_from_slice((ps as u32 * 2).as_slice());
+        match GuestMemory::restore_snapshot(&mut Cursor::new(&bad_page_size)) {
+            Err(Error::InvalidPageSize(size)) => assert_eq!(size as u64, ps * 2),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // A region that runs past the end of the guest address space.
+        let mut bad_region = snapshot.clone();
+        let region_offset = std::mem::size_of::<SnapshotHeader>();
+        bad_region[region_offset..region_offset + 8]
+            .copy_from_slice(0u64.wrapping_sub(ps).as_slice());
+        bad_region[region_offset + 8..region_offset + 16].copy_from_slice((2 * ps).as_slice());
+        match GuestMemory::restore_snapshot(&mut Cursor::new(&bad_region)) {
+            Err(Error::GuestMemory(guest_memory::Error::MemoryRegionTooLarge(_))) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let mut bad_huge_page_size = snapshot.clone();
+        bad_huge_page_size[24..32].copy_from_slice(ps.as_slice());
+        match GuestMemory::restore_snapshot(&mut Cursor::new(&bad_huge_page_size)) {
+            Err(Error::InvalidHugePageSize(size)) => assert_eq!(size, ps),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        let truncated = &snapshot[..snapshot.len() - 1];
+        match GuestMemory::restore_snapshot(&mut Cursor::new(truncated)) {
+            Err(Error::Io(_)) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+}
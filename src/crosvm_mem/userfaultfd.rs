@@ -0,0 +1,563 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Populating guest memory lazily with userfaultfd.
+//!
+//! A `Userfaultfd` is registered with the regions of a `GuestMemory`, or with individual
+//! `MemoryMapping`s. The first access to each page that isn't populated yet then blocks until
+//! `Userfaultfd::handle_faults` fills it in with the contents provided by a `PageSource`, which
+//! lets a VM resume from a snapshot before its memory was read back. The faults have to be handled
+//! on another thread than the ones that access the memory, typically one that polls the
+//! userfaultfd for readability.
+//!
+//! The kernel only supports userfaultfd for anonymous memory, memfds and hugetlbfs, so regions
+//! that are backed by other files can't be registered.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::result;
+use std::sync::Arc;
+
+use libc::{self, c_ulong, c_void};
+
+use super::errno;
+use super::guest_address::GuestAddress;
+use super::guest_memory::GuestMemory;
+use super::mmap::{MappedRegion, MemoryMapping};
+use super::pagesize;
+
+// From the kernel's include/uapi/linux/userfaultfd.h, which the libc crate doesn't cover.
+const UFFD_API: u64 = 0xaa;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+const UFFDIO: c_ulong = 0xaa;
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+const fn ioc(dir: c_ulong, nr: c_ulong, size: usize) -> c_ulong {
+    (dir << 30) | ((size as c_ulong) << 16) | (UFFDIO << 8) | nr
+}
+
+const UFFDIO_API: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x3f, size_of::<UffdioApi>());
+const UFFDIO_REGISTER: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x00, size_of::<UffdioRegister>());
+const UFFDIO_COPY: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x03, size_of::<UffdioCopy>());
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+// `struct uffd_msg`, where `arg` holds the flags, address and thread id of page fault events.
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    arg: [u64; 3],
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the userfaultfd.
+    Create(errno::Error),
+    /// The kernel doesn't support the userfaultfd API.
+    Api(errno::Error),
+    /// Failed to register a mapping with the userfaultfd.
+    Register(errno::Error),
+    /// Failed to read the pending events from the userfaultfd.
+    ReadEvents(errno::Error),
+    /// The userfaultfd reported an event other than a page fault.
+    UnexpectedEvent(u8),
+    /// A page fault was reported at a host address that isn't in any registered mapping.
+    UnknownAddress(u64),
+    /// The page source failed to provide the contents of the page at the guest address.
+    PageSource(GuestAddress, io::Error),
+    /// Failed to populate the page at the guest address.
+    CopyPage(GuestAddress, errno::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Create(e) => write!(f, "failed to create userfaultfd: {}", e),
+            Api(e) => write!(f, "userfaultfd API is not supported: {}", e),
+            Register(e) => write!(f, "failed to register mapping with userfaultfd: {}", e),
+            ReadEvents(e) => write!(f, "failed to read userfaultfd events: {}", e),
+            UnexpectedEvent(event) => write!(f, "unexpected userfaultfd event {:#x}", event),
+            UnknownAddress(addr) => write!(f, "page fault at unregistered address {:#x}", addr),
+            PageSource(addr, e) => write!(
+                f,
+                "failed to get the contents of the page at {:#x}: {}",
+                addr.offset(),
+                e
+            ),
+            CopyPage(addr, e) => write!(
+                f,
+                "failed to populate the page at {:#x}: {}",
+                addr.offset(),
+                e
+            ),
+        }
+    }
+}
+
+/// Provides the contents of the pages that a `Userfaultfd` populates.
+pub trait PageSource {
+    /// Fills `buf` with the contents of the page at `addr`. `buf` is exactly one page long, and
+    /// `addr` is aligned to its length.
+    fn read_page(&mut self, addr: GuestAddress, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// A `PageSource` that reads the pages from an image of the guest physical address space, where
+/// the contents of each guest address are stored at the same file offset. The parts past the end
+/// of the file read as zeros.
+pub struct FilePageSource {
+    file: File,
+}
+
+impl FilePageSource {
+    /// Creates a page source that reads from `file`.
+    pub fn new(file: File) -> FilePageSource {
+        FilePageSource { file }
+    }
+}
+
+impl PageSource for FilePageSource {
+    fn read_page(&mut self, addr: GuestAddress, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            match self
+                .file
+                .read_at(&mut buf[done..], addr.offset() + done as u64)
+            {
+                Ok(0) => break,
+                Ok(count) => done += count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        for b in &mut buf[done..] {
+            *b = 0;
+        }
+        Ok(())
+    }
+}
+
+// A host address range that is registered with a `Userfaultfd`.
+#[derive(Copy, Clone)]
+struct Registration {
+    host_addr: usize,
+    size: usize,
+    guest_base: GuestAddress,
+    page_size: usize,
+}
+
+impl Registration {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.host_addr && addr - self.host_addr < self.size
+    }
+
+    fn overlaps(&self, host_addr: usize, size: usize) -> bool {
+        host_addr < self.host_addr + self.size && self.host_addr < host_addr + size
+    }
+}
+
+/// A userfaultfd that populates the missing pages of the mappings registered with it.
+pub struct Userfaultfd {
+    uffd: File,
+    registrations: Vec<Registration>,
+    // Keep the registered guest memory and mappings mapped for as long as their faults can be
+    // handled.
+    guest_memory: Vec<GuestMemory>,
+    mappings: Vec<Arc<MemoryMapping>>,
+    // Holds the contents of a page while it's copied into place.
+    page: Vec<u8>,
+}
+
+impl Userfaultfd {
+    /// Creates a userfaultfd that no mapping is registered with yet. Its reads don't block, so
+    /// `handle_faults` returns once all the pending faults were handled.
+    pub fn new() -> Result<Userfaultfd> {
+        // Safe because this doesn't touch any memory, and we check the return value.
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(Error::Create(errno::Error::last()));
+        }
+        // Safe because we own the new fd.
+        let uffd = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the kernel only accesses the struct, and we check the return value.
+        let ret = unsafe { libc::ioctl(uffd.as_raw_fd(), UFFDIO_API as _, &mut api) };
+        if ret < 0 {
+            return Err(Error::Api(errno::Error::last()));
+        }
+
+        Ok(Userfaultfd {
+            uffd,
+            registrations: Vec::new(),
+            guest_memory: Vec::new(),
+            mappings: Vec::new(),
+            page: Vec::new(),
+        })
+    }
+
+    /// Registers all the mapped regions of `mem`, so that their missing pages are populated from
+    /// the page source. MMIO regions aren't affected. The regions stay mapped for as long as the
+    /// userfaultfd exists, but the ones that are hotplugged later have to be registered by
+    /// passing the new `GuestMemory` again.
+    ///
+    /// If a region can't be registered, the regions before it stay registered, and their faults
+    /// are still handled.
+    pub fn register_guest_memory(&mut self, mem: &GuestMemory) -> Result<()> {
+        // Keep the memory mapped before any of it is registered, in case a later region fails.
+        self.guest_memory.push(mem.clone());
+        for region in mem.regions() {
+            let mapping = region.mapping();
+            self.register(
+                mapping.as_ptr() as usize,
+                mapping.size(),
+                region.start(),
+                mem.region_page_size(region),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Registers `mapping`, which has to be backed by normal pages, so that its missing pages are
+    /// populated from the page source as if it was mapped at `guest_base`. The mapping stays
+    /// mapped for as long as the userfaultfd exists.
+    pub fn register_mapping(
+        &mut self,
+        mapping: Arc<MemoryMapping>,
+        guest_base: GuestAddress,
+    ) -> Result<()> {
+        self.register(
+            mapping.as_ptr() as usize,
+            mapping.size(),
+            guest_base,
+            pagesize(),
+        )?;
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    fn register(
+        &mut self,
+        host_addr: usize,
+        size: usize,
+        guest_base: GuestAddress,
+        page_size: usize,
+    ) -> Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: host_addr as u64,
+                len: size as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ..Default::default()
+        };
+        // Safe because the kernel only accesses the struct, and registering the range doesn't
+        // change its contents.
+        let ret =
+            unsafe { libc::ioctl(self.uffd.as_raw_fd(), UFFDIO_REGISTER as _, &mut register) };
+        if ret < 0 {
+            return Err(Error::Register(errno::Error::last()));
+        }
+
+        // Whatever was registered at these addresses before has been unmapped since, or is being
+        // registered again.
+        self.registrations.retain(|r| !r.overlaps(host_addr, size));
+        self.registrations.push(Registration {
+            host_addr,
+            size,
+            guest_base,
+            page_size,
+        });
+        Ok(())
+    }
+
+    /// Populates the pages of all the pending faults with the contents from `source`, which wakes
+    /// up the threads that are waiting for them. Returns the number of faults that were handled,
+    /// which is 0 if none was pending.
+    pub fn handle_faults<P: PageSource + ?Sized>(&mut self, source: &mut P) -> Result<usize> {
+        let mut handled = 0;
+        loop {
+            let mut msg = UffdMsg::default();
+            // Safe because the kernel writes at most one message, which fits in `msg`.
+            let ret = unsafe {
+                libc::read(
+                    self.uffd.as_raw_fd(),
+                    &mut msg as *mut UffdMsg as *mut c_void,
+                    size_of::<UffdMsg>(),
+                )
+            };
+            if ret < 0 {
+                let e = errno::Error::last();
+                match e.errno() {
+                    libc::EAGAIN => return Ok(handled),
+                    libc::EINTR => continue,
+                    _ => return Err(Error::ReadEvents(e)),
+                }
+            }
+            if msg.event != UFFD_EVENT_PAGEFAULT {
+                return Err(Error::UnexpectedEvent(msg.event));
+            }
+            self.populate(msg.arg[1], source)?;
+            handled += 1;
+        }
+    }
+
+    // Populates the page that contains the host address `addr`.
+    fn populate<P: PageSource + ?Sized>(&mut self, addr: u64, source: &mut P) -> Result<()> {
+        let registration = *self
+            .registrations
+            .iter()
+            .find(|r| r.contains(addr as usize))
+            .ok_or(Error::UnknownAddress(addr))?;
+        let page_size = registration.page_size;
+        let offset = (addr as usize - registration.host_addr) & !(page_size - 1);
+        // unchecked_add is safe because the registered mappings fit in the guest address space.
+        let guest_addr = registration.guest_base.unchecked_add(offset as u64);
+
+        self.page.resize(page_size, 0);
+        source
+            .read_page(guest_addr, &mut self.page)
+            .map_err(|e| Error::PageSource(guest_addr, e))?;
+
+        let mut copy = UffdioCopy {
+            dst: (registration.host_addr + offset) as u64,
+            src: self.page.as_ptr() as u64,
+            len: page_size as u64,
+            ..Default::default()
+        };
+        // Safe because the kernel only reads `page_size` bytes from the buffer, and only writes to
+        // a page of the registered mapping that wasn't populated yet.
+        let ret = unsafe { libc::ioctl(self.uffd.as_raw_fd(), UFFDIO_COPY as _, &mut copy) };
+        if ret < 0 {
+            let e = errno::Error::last();
+            // The page was populated by another fault on it in the meantime.
+            if e.errno() != libc::EEXIST {
+                return Err(Error::CopyPage(guest_addr, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Userfaultfd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.uffd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::{self, JoinHandle};
+
+    use super::super::guest_memory::{GuestMemoryBuilder, MemoryRegionOptions, RegionBacking};
+    use super::super::shm::SharedMemory;
+
+    // Returns a userfaultfd, or `None` if the kernel doesn't allow us to create one, in which case
+    // the calling test is skipped.
+    fn new_userfaultfd() -> Option<Userfaultfd> {
+        match Userfaultfd::new() {
+            Ok(uffd) => Some(uffd),
+            Err(e @ Error::Create(_)) | Err(e @ Error::Api(_)) => {
+                eprintln!("skipping test, userfaultfd is unavailable: {}", e);
+                None
+            }
+            Err(e) => panic!("failed to create userfaultfd: {}", e),
+        }
+    }
+
+    // Returns a page source for an image with `contents` at each of the given offsets.
+    fn image(contents: &[(u64, &[u8])]) -> FilePageSource {
+        let shm = SharedMemory::anon().unwrap();
+        let file: File = shm.into();
+        for (offset, data) in contents {
+            file.write_all_at(data, *offset).unwrap();
+        }
+        FilePageSource::new(file)
+    }
+
+    // Handles the faults of `uffd` on another thread until `stop` is set, and returns how many
+    // there were.
+    fn serve(
+        mut uffd: Userfaultfd,
+        mut source: FilePageSource,
+        stop: Arc<AtomicBool>,
+    ) -> JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut handled = 0;
+            while !stop.load(Ordering::SeqCst) {
+                let mut pollfd = libc::pollfd {
+                    fd: uffd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // Safe because the kernel only accesses the single pollfd.
+                unsafe { libc::poll(&mut pollfd, 1, 10) };
+                handled += uffd.handle_faults(&mut source).unwrap();
+            }
+            handled
+        })
+    }
+
+    #[test]
+    fn lazy_guest_memory() {
+        let mut uffd = match new_userfaultfd() {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x2000), (GuestAddress(0x4000), 0x1000)])
+            .unwrap();
+        uffd.register_guest_memory(&gm).unwrap();
+        let source = image(&[
+            (0x0, &[0x11; 0x1000]),
+            (0x1ff8, &[0x22; 0x8]),
+            (0x4000, &[0x33; 0x10]),
+        ]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = serve(uffd, source, stop.clone());
+
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x0)).unwrap(),
+            0x1111_1111_1111_1111
+        );
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x1ff8)).unwrap(),
+            0x2222_2222_2222_2222
+        );
+        // The parts past the end of the image are zero, and the populated pages can be written.
+        let mut buf = [0u8; 0x20];
+        gm.read_exact_at_addr(&mut buf, GuestAddress(0x4000))
+            .unwrap();
+        assert_eq!(&buf[..0x10], &[0x33; 0x10]);
+        assert_eq!(&buf[0x10..], &[0; 0x10]);
+        gm.write_obj_at_addr(0x44u8, GuestAddress(0x4010)).unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(0x4010)).unwrap(),
+            0x44
+        );
+
+        stop.store(true, Ordering::SeqCst);
+        // Each page was populated on its first access.
+        assert_eq!(handler.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn lazy_mapping() {
+        let mut uffd = match new_userfaultfd() {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let ps = pagesize();
+        let mapping = Arc::new(MemoryMapping::new(2 * ps).unwrap());
+        uffd.register_mapping(mapping.clone(), GuestAddress(0x10000))
+            .unwrap();
+        let source = image(&[(0x10000 + ps as u64, &[0x55; 0x8])]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = serve(uffd, source, stop.clone());
+
+        assert_eq!(mapping.read_obj::<u64>(ps).unwrap(), 0x5555_5555_5555_5555);
+        assert_eq!(mapping.read_obj::<u64>(0).unwrap(), 0);
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(handler.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn partial_registration() {
+        let mut uffd = match new_userfaultfd() {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let ps = pagesize() as u64;
+        let path = std::env::temp_dir().join(format!("userfaultfd_file_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(ps).unwrap();
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps))
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 4), ps)
+                    .backing(RegionBacking::File(Arc::new(file), 0)),
+            )
+            .build()
+            .unwrap();
+
+        // Only files on shmem or hugetlbfs can be registered, so this fails unless the temporary
+        // directory is a tmpfs. Either way, the memfd region is registered and kept mapped.
+        let _ = uffd.register_guest_memory(&gm);
+        assert_eq!(uffd.guest_memory.len(), 1);
+        let source = image(&[(0x0, &[0x11; 0x8])]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = serve(uffd, source, stop.clone());
+
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x0)).unwrap(),
+            0x1111_1111_1111_1111
+        );
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(handler.join().unwrap(), 1);
+    }
+}
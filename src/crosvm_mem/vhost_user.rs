@@ -0,0 +1,440 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Exchanging guest memory in the layout of the vhost-user `VHOST_USER_SET_MEM_TABLE` message.
+//!
+//! The frontend describes each region by its guest physical address, its size, its address in the
+//! frontend process, and its offset in the fd that is passed along with the message. The backend
+//! maps the same ranges of the fds, and translates the frontend addresses it gets in later
+//! messages, such as the vring addresses, back to guest addresses.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::result;
+use std::sync::Arc;
+
+use super::data_init::DataInit;
+use super::guest_address::GuestAddress;
+use super::guest_memory::{
+    self, GuestMemory, GuestMemoryBuilder, MemoryRegionOptions, RegionBacking,
+};
+use super::mmap::MappedRegion;
+use super::sharing::{recv_with_fds, send_with_fds, MAX_FDS};
+
+/// The request code of `VHOST_USER_SET_MEM_TABLE`.
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+/// The maximum number of regions in a vhost-user memory table.
+pub const VHOST_USER_MAX_REGIONS: usize = MAX_FDS;
+
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_VERSION_MASK: u32 = 0x3;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The message isn't a `VHOST_USER_SET_MEM_TABLE` request.
+    InvalidRequest(u32),
+    /// The message was sent with an unsupported version of the protocol.
+    UnsupportedVersion(u32),
+    /// The payload size doesn't match the number of regions.
+    InvalidPayloadSize(u32),
+    /// The memory table has more regions than vhost-user supports.
+    TooManyRegions(usize),
+    /// The number of fds doesn't match the number of regions.
+    FdCountMismatch { regions: usize, fds: usize },
+    /// The region runs past the end of the guest address space.
+    RegionOutOfRange(VhostUserMemoryRegion),
+    /// The guest memory can't be exported, or can't be built from the memory table.
+    GuestMemory(guest_memory::Error),
+    /// Sending or receiving the message failed.
+    Io(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidRequest(request) => write!(f, "unexpected vhost-user request {}", request),
+            UnsupportedVersion(flags) => {
+                write!(f, "unsupported vhost-user version in flags {:#x}", flags)
+            }
+            InvalidPayloadSize(size) => {
+                write!(f, "invalid vhost-user memory table size {}", size)
+            }
+            TooManyRegions(count) => write!(f, "too many vhost-user memory regions: {}", count),
+            FdCountMismatch { regions, fds } => write!(
+                f,
+                "got {} fds for {} vhost-user memory regions",
+                fds, regions
+            ),
+            RegionOutOfRange(region) => write!(
+                f,
+                "vhost-user memory region at {:#x} of size {:#x} is out of range",
+                region.guest_phys_addr, region.memory_size
+            ),
+            GuestMemory(e) => write!(f, "failed to exchange guest memory: {}", e),
+            Io(e) => write!(f, "failed to transfer vhost-user memory table: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<guest_memory::Error> for Error {
+    fn from(e: guest_memory::Error) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VhostUserHeader {
+    request: u32,
+    flags: u32,
+    size: u32,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for VhostUserHeader {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VhostUserMemoryHeader {
+    nregions: u32,
+    padding: u32,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for VhostUserMemoryHeader {}
+
+/// A region in the payload of `VHOST_USER_SET_MEM_TABLE`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VhostUserMemoryRegion {
+    /// The guest physical address of the region.
+    pub guest_phys_addr: u64,
+    /// The size of the region in bytes.
+    pub memory_size: u64,
+    /// The address of the region in the frontend process.
+    pub userspace_addr: u64,
+    /// The offset of the region in its fd.
+    pub mmap_offset: u64,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for VhostUserMemoryRegion {}
+
+impl VhostUserMemoryRegion {
+    // Returns the guest address of the frontend address `userspace_addr`, if it's in this region.
+    fn frontend_to_guest(&self, userspace_addr: u64) -> Option<GuestAddress> {
+        let offset = userspace_addr.checked_sub(self.userspace_addr)?;
+        if offset < self.memory_size {
+            self.guest_phys_addr.checked_add(offset).map(GuestAddress)
+        } else {
+            None
+        }
+    }
+}
+
+impl GuestMemory {
+    /// Returns the vhost-user memory table of this guest memory, with the fd that backs each
+    /// region. The `userspace_addr` of each region is its host address in this process.
+    ///
+    /// Fails with `AnonymousRegion` if a region is anonymous, since it can't be shared. MMIO regions
+    /// are left out, since their handlers are local to this process.
+    pub fn vhost_user_mem_table(&self) -> Result<Vec<(VhostUserMemoryRegion, RawFd)>> {
+        let mut table = Vec::new();
+        for region in self.regions() {
+            let (fd, mmap_offset) = region
+                .fd_offset(self.as_ref())
+                .ok_or(guest_memory::Error::AnonymousRegion(region.start()))?;
+            table.push((
+                VhostUserMemoryRegion {
+                    guest_phys_addr: region.start().offset(),
+                    memory_size: region.mapping().size() as u64,
+                    userspace_addr: region.mapping().as_ptr() as u64,
+                    mmap_offset,
+                },
+                fd.as_raw_fd(),
+            ));
+        }
+        if table.len() > VHOST_USER_MAX_REGIONS {
+            return Err(Error::TooManyRegions(table.len()));
+        }
+        Ok(table)
+    }
+
+    /// Sends a `VHOST_USER_SET_MEM_TABLE` request with the memory table of this guest memory over
+    /// `socket`. The request doesn't ask for a reply.
+    pub fn send_vhost_user_mem_table(&self, socket: &UnixStream) -> Result<()> {
+        let table = self.vhost_user_mem_table()?;
+        let payload_size =
+            size_of::<VhostUserMemoryHeader>() + table.len() * size_of::<VhostUserMemoryRegion>();
+        let header = VhostUserHeader {
+            request: VHOST_USER_SET_MEM_TABLE,
+            flags: VHOST_USER_VERSION,
+            size: payload_size as u32,
+        };
+        let memory_header = VhostUserMemoryHeader {
+            nregions: table.len() as u32,
+            padding: 0,
+        };
+
+        let mut message = header.as_slice().to_vec();
+        message.extend_from_slice(memory_header.as_slice());
+        for (region, _) in &table {
+            message.extend_from_slice(region.as_slice());
+        }
+        let fds: Vec<RawFd> = table.iter().map(|&(_, fd)| fd).collect();
+
+        let sent = send_with_fds(socket, &message, &fds)?;
+        (&*socket).write_all(&message[sent..])?;
+        Ok(())
+    }
+}
+
+/// Guest memory that a vhost-user backend built from the memory table of its frontend.
+pub struct VhostUserGuestMemory {
+    mem: GuestMemory,
+    // Sorted by guest address, like the regions of `mem`.
+    regions: Vec<VhostUserMemoryRegion>,
+}
+
+impl VhostUserGuestMemory {
+    /// Maps the regions of a vhost-user memory table, where `fds` holds the fd of each region in
+    /// the same order. The regions are shared mappings of the fds, so writes to them are visible
+    /// to the frontend.
+    pub fn new(regions: &[VhostUserMemoryRegion], fds: Vec<File>) -> Result<VhostUserGuestMemory> {
+        if regions.len() > VHOST_USER_MAX_REGIONS {
+            return Err(Error::TooManyRegions(regions.len()));
+        }
+        if regions.len() != fds.len() {
+            return Err(Error::FdCountMismatch {
+                regions: regions.len(),
+                fds: fds.len(),
+            });
+        }
+
+        // The builder only checks that the regions don't overlap each other.
+        if let Some(&region) = regions.iter().find(|region| {
+            region
+                .guest_phys_addr
+                .checked_add(region.memory_size)
+                .is_none()
+        }) {
+            return Err(Error::RegionOutOfRange(region));
+        }
+
+        let mut table: Vec<(VhostUserMemoryRegion, File)> =
+            regions.iter().copied().zip(fds).collect();
+        table.sort_by_key(|(region, _)| region.guest_phys_addr);
+
+        let mut builder = GuestMemoryBuilder::new();
+        for (region, file) in table.iter() {
+            let backing = RegionBacking::File(Arc::new(file.try_clone()?), region.mmap_offset);
+            builder = builder.region(
+                MemoryRegionOptions::new(GuestAddress(region.guest_phys_addr), region.memory_size)
+                    .backing(backing),
+            );
+        }
+
+        Ok(VhostUserGuestMemory {
+            mem: builder.build()?,
+            regions: table.into_iter().map(|(region, _)| region).collect(),
+        })
+    }
+
+    /// Receives a `VHOST_USER_SET_MEM_TABLE` request from `socket`, and maps its regions.
+    pub fn recv(socket: &UnixStream) -> Result<VhostUserGuestMemory> {
+        let mut header = VhostUserHeader::default();
+        let (count, fds) = recv_with_fds(socket, header.as_mut_slice(), MAX_FDS)?;
+        if count == 0 {
+            return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        (&*socket).read_exact(&mut header.as_mut_slice()[count..])?;
+
+        if header.request != VHOST_USER_SET_MEM_TABLE {
+            return Err(Error::InvalidRequest(header.request));
+        }
+        if header.flags & VHOST_USER_VERSION_MASK != VHOST_USER_VERSION {
+            return Err(Error::UnsupportedVersion(header.flags));
+        }
+        let memory_header = VhostUserMemoryHeader::from_reader(&mut &*socket)?;
+        let nregions = memory_header.nregions as usize;
+        if nregions > VHOST_USER_MAX_REGIONS {
+            return Err(Error::TooManyRegions(nregions));
+        }
+        let payload_size =
+            size_of::<VhostUserMemoryHeader>() + nregions * size_of::<VhostUserMemoryRegion>();
+        if header.size as usize != payload_size {
+            return Err(Error::InvalidPayloadSize(header.size));
+        }
+
+        let mut regions = Vec::with_capacity(nregions);
+        for _ in 0..nregions {
+            regions.push(VhostUserMemoryRegion::from_reader(&mut &*socket)?);
+        }
+        VhostUserGuestMemory::new(&regions, fds)
+    }
+
+    /// Returns the guest memory that the regions are mapped in.
+    pub fn guest_memory(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    /// Returns the regions of the memory table, sorted by guest address.
+    pub fn regions(&self) -> &[VhostUserMemoryRegion] {
+        &self.regions
+    }
+
+    /// Translates an address in the frontend process to the guest address it maps, or returns
+    /// `None` if it isn't in any of the regions.
+    pub fn frontend_to_guest(&self, userspace_addr: u64) -> Option<GuestAddress> {
+        self.regions
+            .iter()
+            .find_map(|region| region.frontend_to_guest(userspace_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+
+    use super::super::pagesize;
+    use super::super::shm::SharedMemory;
+
+    #[test]
+    fn mem_table() {
+        let ps = pagesize() as u64;
+        let mut file = SharedMemory::anon().unwrap();
+        file.set_size(ps * 4).unwrap();
+        let file: Arc<File> = Arc::new(file.into());
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::new(GuestAddress(0x0), ps * 2))
+            .region(MemoryRegionOptions::new(GuestAddress(ps * 4), ps))
+            .region(
+                MemoryRegionOptions::new(GuestAddress(ps * 8), ps)
+                    .backing(RegionBacking::File(file.clone(), ps * 2)),
+            )
+            .build()
+            .unwrap();
+
+        let table = gm.vhost_user_mem_table().unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table[1].0.guest_phys_addr, ps * 4);
+        assert_eq!(table[1].0.mmap_offset, ps * 2);
+        assert_eq!(table[1].1, gm.as_raw_fd());
+        assert_eq!(table[2].0.mmap_offset, ps * 2);
+        assert_eq!(table[2].1, file.as_raw_fd());
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        gm.send_vhost_user_mem_table(&frontend).unwrap();
+        let received = VhostUserGuestMemory::recv(&backend).unwrap();
+        let mem = received.guest_memory();
+        assert_eq!(mem.num_regions(), 3);
+        assert_eq!(
+            received.regions(),
+            &[table[0].0, table[1].0, table[2].0][..]
+        );
+
+        // Both sides see each other's writes.
+        gm.write_obj_at_addr(0x1122_3344u32, GuestAddress(ps * 4 + 8))
+            .unwrap();
+        assert_eq!(
+            mem.read_obj_from_addr::<u32>(GuestAddress(ps * 4 + 8))
+                .unwrap(),
+            0x1122_3344
+        );
+        mem.write_obj_at_addr(0x55u8, GuestAddress(ps * 8 + 1))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(ps * 8 + 1))
+                .unwrap(),
+            0x55
+        );
+
+        // Frontend addresses are translated back to guest addresses.
+        let host_addr = gm.get_host_address(GuestAddress(ps * 8 + 0x10)).unwrap() as u64;
+        assert_eq!(
+            received.frontend_to_guest(host_addr),
+            Some(GuestAddress(ps * 8 + 0x10))
+        );
+        assert_eq!(received.frontend_to_guest(0), None);
+    }
+
+    #[test]
+    fn mem_table_invalid() {
+        let ps = pagesize() as u64;
+        let gm = GuestMemoryBuilder::new()
+            .region(
+                MemoryRegionOptions::new(GuestAddress(0x0), ps).backing(RegionBacking::Anonymous),
+            )
+            .build()
+            .unwrap();
+        match gm.vhost_user_mem_table() {
+            Err(Error::GuestMemory(guest_memory::Error::AnonymousRegion(GuestAddress(0)))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let region = VhostUserMemoryRegion {
+            guest_phys_addr: 0,
+            memory_size: ps,
+            userspace_addr: 0,
+            mmap_offset: 0,
+        };
+        match VhostUserGuestMemory::new(&[region], Vec::new()) {
+            Err(Error::FdCountMismatch { regions: 1, fds: 0 }) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // Regions must end within the guest address space.
+        let region = VhostUserMemoryRegion {
+            guest_phys_addr: 0u64.wrapping_sub(ps),
+            memory_size: 2 * ps,
+            ..region
+        };
+        let file: File = SharedMemory::anon().unwrap().into();
+        match VhostUserGuestMemory::new(&[region], vec![file]) {
+            Err(Error::RegionOutOfRange(r)) => assert_eq!(r, region),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+        let region = VhostUserMemoryRegion {
+            userspace_addr: 0x1000,
+            ..region
+        };
+        assert_eq!(
+            region.frontend_to_guest(0x1000 + ps - 1),
+            Some(GuestAddress(u64::MAX))
+        );
+        assert_eq!(region.frontend_to_guest(0x1000 + ps), None);
+
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let header = VhostUserHeader {
+            request: VHOST_USER_SET_MEM_TABLE + 1,
+            flags: VHOST_USER_VERSION,
+            size: 0,
+        };
+        (&frontend).write_all(header.as_slice()).unwrap();
+        match VhostUserGuestMemory::recv(&backend) {
+            Err(Error::InvalidRequest(6)) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+}
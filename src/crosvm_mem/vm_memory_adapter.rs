@@ -0,0 +1,331 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE-BSD-3-Clause file.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Implementations of the upstream vm-memory interfaces for the crosvm memory model.
+//!
+//! With the `vm-memory` feature enabled, `GuestMemory` implements `vm_memory::GuestMemory`, and
+//! therefore `vm_memory::Bytes<vm_memory::GuestAddress>`, while its regions implement
+//! `vm_memory::GuestMemoryRegion`. This lets code that is written against vm-memory run on top
+//! of either memory model. MMIO regions aren't visible through these interfaces, and writes to
+//! read-only regions fail with a `PermissionDenied` I/O error.
+
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::result;
+use std::slice;
+
+use vm_memory::{
+    Address, Bytes, GuestAddress as VmGuestAddress, GuestMemory as VmGuestMemory, GuestMemoryError,
+    GuestMemoryRegion, GuestUsize, MemoryRegionAddress,
+};
+
+use super::guest_address::GuestAddress;
+use super::guest_memory::{GuestMemory, MemoryRegion};
+use super::mmap::MappedRegion;
+
+type Result<T> = result::Result<T, GuestMemoryError>;
+
+impl MemoryRegion {
+    // Returns the offset of `addr` in the region, and how many of `count` bytes from there fit in
+    // it.
+    fn access_range(&self, addr: MemoryRegionAddress, count: usize) -> Result<(usize, usize)> {
+        let size = self.mapping().size();
+        let offset = addr.raw_value();
+        if offset >= size as u64 {
+            return Err(GuestMemoryError::InvalidBackendAddress);
+        }
+        let offset = offset as usize;
+        Ok((offset, min(count, size - offset)))
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.writable() {
+            Ok(())
+        } else {
+            Err(GuestMemoryError::IOError(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "write to a read-only memory region",
+            )))
+        }
+    }
+
+    // Returns the host address of the byte at `offset`, which must be in bounds.
+    fn host_ptr(&self, offset: usize) -> *mut u8 {
+        // This is safe because `offset` is within the mapping.
+        unsafe { self.mapping().as_ptr().add(offset) }
+    }
+}
+
+impl Bytes<MemoryRegionAddress> for MemoryRegion {
+    type E = GuestMemoryError;
+
+    fn write(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<usize> {
+        self.check_writable()?;
+        let (offset, _) = self.access_range(addr, buf.len())?;
+        let count = self
+            .mapping()
+            .write_slice(buf, offset)
+            .map_err(|_| GuestMemoryError::InvalidBackendAddress)?;
+        self.mark_dirty(offset, count);
+        Ok(count)
+    }
+
+    fn read(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<usize> {
+        let (offset, _) = self.access_range(addr, buf.len())?;
+        self.mapping()
+            .read_slice(buf, offset)
+            .map_err(|_| GuestMemoryError::InvalidBackendAddress)
+    }
+
+    fn write_slice(&self, buf: &[u8], addr: MemoryRegionAddress) -> Result<()> {
+        let completed = self.write(buf, addr)?;
+        if completed != buf.len() {
+            return Err(GuestMemoryError::PartialBuffer {
+                expected: buf.len(),
+                completed,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_slice(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> Result<()> {
+        let completed = self.read(buf, addr)?;
+        if completed != buf.len() {
+            return Err(GuestMemoryError::PartialBuffer {
+                expected: buf.len(),
+                completed,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_from<F>(&self, addr: MemoryRegionAddress, src: &mut F, count: usize) -> Result<usize>
+    where
+        F: Read,
+    {
+        self.check_writable()?;
+        let (offset, len) = self.access_range(addr, count)?;
+        // This is safe because the range was checked above, and the slice doesn't outlive the
+        // mapping.
+        let dst = unsafe { slice::from_raw_parts_mut(self.host_ptr(offset), len) };
+        loop {
+            match src.read(dst) {
+                Ok(completed) => {
+                    self.mark_dirty(offset, completed);
+                    return Ok(completed);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(GuestMemoryError::IOError(e)),
+            }
+        }
+    }
+
+    fn read_exact_from<F>(&self, addr: MemoryRegionAddress, src: &mut F, count: usize) -> Result<()>
+    where
+        F: Read,
+    {
+        self.check_writable()?;
+        let (offset, len) = self.access_range(addr, count)?;
+        // This is safe because the range was checked above, and the slice doesn't outlive the
+        // mapping.
+        let dst = unsafe { slice::from_raw_parts_mut(self.host_ptr(offset), len) };
+        let res = src.read_exact(dst);
+        // Part of the data may have been written even if the read failed.
+        self.mark_dirty(offset, len);
+        res.map_err(GuestMemoryError::IOError)?;
+        if len != count {
+            return Err(GuestMemoryError::PartialBuffer {
+                expected: count,
+                completed: len,
+            });
+        }
+        Ok(())
+    }
+
+    fn write_to<F>(&self, addr: MemoryRegionAddress, dst: &mut F, count: usize) -> Result<usize>
+    where
+        F: Write,
+    {
+        let (offset, len) = self.access_range(addr, count)?;
+        // This is safe because the range was checked above, and the slice doesn't outlive the
+        // mapping.
+        let src = unsafe { slice::from_raw_parts(self.host_ptr(offset), len) };
+        loop {
+            match dst.write(src) {
+                Ok(completed) => return Ok(completed),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(GuestMemoryError::IOError(e)),
+            }
+        }
+    }
+
+    fn write_all_to<F>(&self, addr: MemoryRegionAddress, dst: &mut F, count: usize) -> Result<()>
+    where
+        F: Write,
+    {
+        let (offset, len) = self.access_range(addr, count)?;
+        // This is safe because the range was checked above, and the slice doesn't outlive the
+        // mapping.
+        let src = unsafe { slice::from_raw_parts(self.host_ptr(offset), len) };
+        dst.write_all(src).map_err(GuestMemoryError::IOError)?;
+        if len != count {
+            return Err(GuestMemoryError::PartialBuffer {
+                expected: count,
+                completed: len,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl GuestMemoryRegion for MemoryRegion {
+    fn len(&self) -> GuestUsize {
+        self.mapping().size() as GuestUsize
+    }
+
+    fn start_addr(&self) -> VmGuestAddress {
+        VmGuestAddress(self.start().offset())
+    }
+
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        Some(slice::from_raw_parts(
+            self.host_ptr(0),
+            self.mapping().size(),
+        ))
+    }
+
+    unsafe fn as_mut_slice(&self) -> Option<&mut [u8]> {
+        if self.writable() {
+            Some(slice::from_raw_parts_mut(
+                self.host_ptr(0),
+                self.mapping().size(),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl VmGuestMemory for GuestMemory {
+    type R = MemoryRegion;
+
+    fn num_regions(&self) -> usize {
+        self.regions().len()
+    }
+
+    fn find_region(&self, addr: VmGuestAddress) -> Option<&MemoryRegion> {
+        GuestMemory::find_region(self, GuestAddress(addr.raw_value()))
+    }
+
+    fn with_regions<F, E>(&self, cb: F) -> result::Result<(), E>
+    where
+        F: Fn(usize, &MemoryRegion) -> result::Result<(), E>,
+    {
+        for (index, region) in self.regions().iter().enumerate() {
+            cb(index, region)?;
+        }
+        Ok(())
+    }
+
+    fn with_regions_mut<F, E>(&self, mut cb: F) -> result::Result<(), E>
+    where
+        F: FnMut(usize, &MemoryRegion) -> result::Result<(), E>,
+    {
+        for (index, region) in self.regions().iter().enumerate() {
+            cb(index, region)?;
+        }
+        Ok(())
+    }
+
+    fn map_and_fold<F, G, T>(&self, init: T, mapf: F, foldf: G) -> T
+    where
+        F: Fn((usize, &MemoryRegion)) -> T,
+        G: Fn(T, T) -> T,
+    {
+        self.regions()
+            .iter()
+            .enumerate()
+            .map(mapf)
+            .fold(init, foldf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use super::super::guest_memory::{GuestMemoryBuilder, MemoryRegionOptions};
+
+    // Stands in for a device that is written against the upstream interfaces.
+    fn checksum<M: VmGuestMemory>(mem: &M, addr: VmGuestAddress, len: usize) -> u32 {
+        let mut buf = vec![0u8; len];
+        mem.read_slice(&mut buf, addr).unwrap();
+        buf.iter().map(|&b| u32::from(b)).sum()
+    }
+
+    #[test]
+    fn upstream_interfaces() {
+        let gm = GuestMemory::new(&[
+            (GuestAddress(0x0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x4000), 0x1000),
+        ])
+        .unwrap();
+
+        assert_eq!(VmGuestMemory::num_regions(&gm), 3);
+        let region = VmGuestMemory::find_region(&gm, VmGuestAddress(0x1800)).unwrap();
+        assert_eq!(region.start_addr(), VmGuestAddress(0x1000));
+        assert_eq!(region.len(), 0x1000);
+        assert!(VmGuestMemory::find_region(&gm, VmGuestAddress(0x2000)).is_none());
+
+        // Accesses through the upstream interfaces are split across adjacent regions, and show up
+        // through the native ones.
+        Bytes::write_slice(&gm, &[1u8; 0x10], VmGuestAddress(0xff8)).unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x1000)).unwrap(),
+            0x0101_0101_0101_0101
+        );
+        assert_eq!(checksum(&gm, VmGuestAddress(0xff0), 0x20), 0x10);
+        assert!(Bytes::write_slice(&gm, &[1u8; 0x10], VmGuestAddress(0x1ff8)).is_err());
+
+        Bytes::read_exact_from(
+            &gm,
+            VmGuestAddress(0x4000),
+            &mut Cursor::new(vec![2u8; 0x10]),
+            0x10,
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        Bytes::write_all_to(&gm, VmGuestAddress(0x4008), &mut out, 0x10).unwrap();
+        assert_eq!(&out[..8], &[2u8; 8]);
+        assert_eq!(&out[8..], &[0u8; 8]);
+    }
+
+    #[test]
+    fn upstream_read_only_region() {
+        let gm = GuestMemoryBuilder::new()
+            .region(MemoryRegionOptions::rom(
+                GuestAddress(0x0),
+                0x1000,
+                &[0xaau8][..],
+            ))
+            .build()
+            .unwrap();
+
+        let region = VmGuestMemory::find_region(&gm, VmGuestAddress(0x0)).unwrap();
+        assert!(unsafe { region.as_mut_slice() }.is_none());
+        assert_eq!(unsafe { region.as_slice() }.unwrap()[0], 0xaa);
+        match Bytes::write_slice(&gm, &[0u8], VmGuestAddress(0x0)) {
+            Err(GuestMemoryError::IOError(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied)
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
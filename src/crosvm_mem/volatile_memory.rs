@@ -39,6 +39,10 @@ pub enum VolatileMemoryError {
     OutOfBounds { addr: usize },
     /// Taking a slice at `base` with `offset` would overflow `usize`.
     Overflow { base: usize, offset: usize },
+    /// Nothing is mapped at `addr` in the volatile memory.
+    NotMapped { addr: usize },
+    /// The memory at `addr` is mapped without the access that a slice allows.
+    AccessDenied { addr: usize },
 }
 
 impl Display for VolatileMemoryError {
@@ -52,6 +56,8 @@ impl Display for VolatileMemoryError {
                 "address 0x{:x} offset by 0x{:x} would overflow",
                 base, offset
             ),
+            NotMapped { addr } => write!(f, "nothing is mapped at address 0x{:x}", addr),
+            AccessDenied { addr } => write!(f, "address 0x{:x} is not readable and writable", addr),
         }
     }
 }